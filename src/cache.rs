@@ -0,0 +1,204 @@
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "md5" => Some(Algorithm::Md5),
+            "sha256" => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+// a file is assumed to be unchanged as long as none of these change
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    algorithm: Algorithm,
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    ctime: i64,
+    ctime_nsec: i64,
+}
+
+impl Key {
+    fn new(algorithm: Algorithm, meta: &std::fs::Metadata) -> Self {
+        Self {
+            algorithm,
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+        }
+    }
+
+    fn parse(line: &str) -> Option<(Self, String)> {
+        let mut fields = line.split(' ');
+        let algorithm = Algorithm::from_name(fields.next()?)?;
+        let mut num = || fields.next()?.parse::<i64>().ok();
+        let key = Self {
+            algorithm,
+            dev: num()? as u64,
+            ino: num()? as u64,
+            size: num()? as u64,
+            mtime: num()?,
+            mtime_nsec: num()?,
+            ctime: num()?,
+            ctime_nsec: num()?,
+        };
+        let hash = fields.next()?.to_string();
+        Some((key, hash))
+    }
+}
+
+/// remembers file hashes between runs, keyed by inode, size & times
+pub struct HashCache {
+    path: Option<PathBuf>,
+    rehash: bool,
+    previous: HashMap<Key, String>,
+    current: Mutex<HashMap<Key, String>>,
+}
+
+impl HashCache {
+    /// a cache that always hashes files and is never saved
+    pub fn disabled() -> Self {
+        Self {
+            path: None,
+            rehash: true,
+            previous: HashMap::new(),
+            current: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// loads the cache at path. with rehash the existing entries are not
+    /// used, but the cache is still updated.
+    pub fn open<P: AsRef<Path>>(path: P, rehash: bool) -> Self {
+        let path = path.as_ref();
+        let mut previous = HashMap::new();
+        if !rehash {
+            match std::fs::read_to_string(path) {
+                Ok(contents) => previous.extend(contents.lines().filter_map(Key::parse)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                Err(err) => warn!("failed to read hash cache {}: {}", path.display(), err),
+            }
+        }
+        Self {
+            path: Some(path.to_path_buf()),
+            rehash,
+            previous,
+            current: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn md5<P: AsRef<Path>>(&self, path: P) -> Result<String> {
+        self.hash(Algorithm::Md5, path.as_ref())
+    }
+
+    pub fn sha256<P: AsRef<Path>>(&self, path: P) -> Result<String> {
+        self.hash(Algorithm::Sha256, path.as_ref())
+    }
+
+    fn hash(&self, algorithm: Algorithm, path: &Path) -> Result<String> {
+        let meta = std::fs::metadata(path)
+            .with_context(|| format!("failed to stat {}", path.display()))?;
+        let key = Key::new(algorithm, &meta);
+        if !self.rehash {
+            let cached = self.current.lock().unwrap().get(&key).cloned();
+            if let Some(hash) = cached.or_else(|| self.previous.get(&key).cloned()) {
+                self.current.lock().unwrap().insert(key, hash.clone());
+                return Ok(hash);
+            }
+        }
+        // alpm doesn't say why hashing failed, opening first gives the reason
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let bytes = path.as_os_str().as_bytes();
+        let hash = match algorithm {
+            Algorithm::Md5 => alpm::compute_md5sum(bytes),
+            Algorithm::Sha256 => alpm::compute_sha256sum(bytes),
+        }
+        .map_err(|_| anyhow!("failed to hash {}", path.display()))?;
+        if self.path.is_some() {
+            self.current.lock().unwrap().insert(key, hash.clone());
+        }
+        Ok(hash)
+    }
+
+    /// writes the hashes used in this run, dropping entries for files that
+    /// were not looked at unless keep_previous is set. runs that only check
+    /// part of the system keep the previous entries.
+    pub fn save(&self, keep_previous: bool) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        // the hashes of files only root can read are kept, like the ones of
+        // etc/shadow, so only root may read the cache
+        if let Some(parent) = path.parent() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+        }
+        let tmp = path.with_extension("tmp");
+        // the mode only applies to new files
+        let _ = std::fs::remove_file(&tmp);
+        let mut file = std::io::BufWriter::new(
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&tmp)
+                .with_context(|| format!("failed to create {}", tmp.display()))?,
+        );
+        let current = self.current.lock().unwrap();
+        let previous = self
+            .previous
+            .iter()
+            .filter(|(key, _)| keep_previous && !current.contains_key(key));
+        for (key, hash) in current.iter().chain(previous) {
+            writeln!(
+                file,
+                "{} {} {} {} {} {} {} {} {}",
+                key.algorithm.name(),
+                key.dev,
+                key.ino,
+                key.size,
+                key.mtime,
+                key.mtime_nsec,
+                key.ctime,
+                key.ctime_nsec,
+                hash
+            )?;
+        }
+        file.flush()?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("failed to rename {} to {}", tmp.display(), path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use crate::escape::{escape, unescape};
+use anyhow::{anyhow, Error};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// the kind of difference found for a path
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Status {
+    /// unpackaged file that isn't tracked in the repo
+    Untracked,
+    /// modified backup file
+    Backup,
+    /// packaged file that has been deleted
+    Deleted,
+    /// file that differs from its repo copy
+    Repo,
+    /// file tracked in the repo that is missing on the system
+    Missing,
+    /// packaged file that differs from the package mtree
+    Mtree,
+    /// new version of a backup file that pacman didn't install
+    Pacnew,
+    /// backup file pacman kept when its package was removed
+    Pacsave,
+    /// file whose mode, owner or symlink target differs from the repo
+    /// manifest
+    Metadata,
+    /// path that couldn't be checked, like a file that can't be read
+    Error,
+}
+
+impl Status {
+    pub const ALL: [Status; 10] = [
+        Status::Untracked,
+        Status::Backup,
+        Status::Deleted,
+        Status::Repo,
+        Status::Missing,
+        Status::Mtree,
+        Status::Pacnew,
+        Status::Pacsave,
+        Status::Metadata,
+        Status::Error,
+    ];
+
+    pub fn as_char(self) -> char {
+        match self {
+            Status::Untracked => '?',
+            Status::Backup => 'B',
+            Status::Deleted => 'D',
+            Status::Repo => 'R',
+            Status::Missing => 'X',
+            Status::Mtree => 'M',
+            Status::Pacnew => 'N',
+            Status::Pacsave => 'S',
+            Status::Metadata => 'P',
+            Status::Error => 'E',
+        }
+    }
+
+    pub fn from_char(c: char) -> Option<Self> {
+        match c {
+            '?' => Some(Status::Untracked),
+            'B' => Some(Status::Backup),
+            'D' => Some(Status::Deleted),
+            'R' => Some(Status::Repo),
+            'X' => Some(Status::Missing),
+            'M' => Some(Status::Mtree),
+            'N' => Some(Status::Pacnew),
+            'S' => Some(Status::Pacsave),
+            'P' => Some(Status::Metadata),
+            'E' => Some(Status::Error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_char())
+    }
+}
+
+impl FromStr for Status {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        match (chars.next().and_then(Status::from_char), chars.next()) {
+            (Some(status), None) => Ok(status),
+            _ => Err(anyhow!("unknown status {}", s)),
+        }
+    }
+}
+
+impl Serialize for Status {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_char(self.as_char())
+    }
+}
+
+impl<'de> Deserialize<'de> for Status {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let c = char::deserialize(deserializer)?;
+        Status::from_char(c).ok_or_else(|| de::Error::custom(format!("unknown status {}", c)))
+    }
+}
+
+/// how a change compares to an earlier snapshot
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Drift {
+    /// not in the snapshot
+    Appeared,
+    /// only in the snapshot
+    Disappeared,
+    /// in the snapshot with a different hash
+    Changed,
+}
+
+impl Drift {
+    pub fn as_char(self) -> char {
+        match self {
+            Drift::Appeared => '+',
+            Drift::Disappeared => '-',
+            Drift::Changed => '~',
+        }
+    }
+}
+
+/// what is known about a difference besides its status
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Details {
+    pub expected_hash: Option<String>,
+    pub actual_hash: Option<String>,
+    pub error: Option<String>,
+    /// how the path relates to another file, like a pacnew to its base file
+    pub note: Option<String>,
+}
+
+fn serialize_path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&escape(path))
+}
+
+fn deserialize_path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    Ok(unescape(&String::deserialize(deserializer)?))
+}
+
+/// a single difference found by a scan. the path is relative to the root and
+/// serialized escaped.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Change {
+    pub status: Status,
+    #[serde(
+        serialize_with = "serialize_path",
+        deserialize_with = "deserialize_path"
+    )]
+    pub path: PathBuf,
+    pub package: Option<String>,
+    pub version: Option<String>,
+    #[serde(flatten)]
+    pub details: Details,
+    /// set when comparing with a snapshot
+    #[serde(default)]
+    pub drift: Option<Drift>,
+}
+
+impl Change {
+    pub fn new(status: Status, path: PathBuf) -> Self {
+        Self {
+            status,
+            path,
+            package: None,
+            version: None,
+            details: Details::default(),
+            drift: None,
+        }
+    }
+
+    // sets the owning package from its name & version
+    pub(crate) fn owned_by(self, pkg: Option<&(String, String)>) -> Self {
+        match pkg {
+            Some((name, version)) => Self {
+                package: Some(name.clone()),
+                version: Some(version.clone()),
+                ..self
+            },
+            None => self,
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+fn needs_escape(c: char) -> bool {
+    c == '\\' || c.is_control()
+}
+
+/// turns a path into text without losing anything, since paths are bytes on
+/// linux. backslashes are doubled, control characters & bytes that aren't
+/// valid utf-8 become \xNN and everything else is kept.
+pub fn escape(path: &Path) -> Cow<'_, str> {
+    let bytes = path.as_os_str().as_bytes();
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.chars().any(needs_escape) => return Cow::Borrowed(s),
+        _ => (),
+    }
+    let mut out = String::with_capacity(bytes.len() + 8);
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        out.push_str(&format!("\\x{:02x}", b));
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// reverses escape. malformed escapes are kept as they are.
+pub fn unescape(s: &str) -> PathBuf {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            if bytes.get(i + 1) == Some(&b'\\') {
+                out.push(b'\\');
+                i += 2;
+                continue;
+            }
+            if bytes.get(i + 1) == Some(&b'x') && i + 4 <= bytes.len() {
+                let digits = std::str::from_utf8(&bytes[i + 2..i + 4]).unwrap_or("");
+                if let Ok(b) = u8::from_str_radix(digits, 16) {
+                    out.push(b);
+                    i += 4;
+                    continue;
+                }
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    PathBuf::from(OsString::from_vec(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(bytes: &[u8]) -> PathBuf {
+        PathBuf::from(OsString::from_vec(bytes.to_vec()))
+    }
+
+    #[test]
+    fn plain_paths_are_borrowed() {
+        let p = Path::new("/etc/pacman.d/mirror list ü");
+        assert!(matches!(
+            escape(p),
+            Cow::Borrowed("/etc/pacman.d/mirror list ü")
+        ));
+    }
+
+    #[test]
+    fn escaped() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"a\\b", "a\\\\b"),
+            (b"a\nb\tc\x7f", "a\\x0ab\\x09c\\x7f"),
+            ("a\u{85}b".as_bytes(), "a\\xc2\\x85b"),
+            (b"a\xffb\xc3", "a\\xffb\\xc3"),
+            (b"a\\x41", "a\\\\x41"),
+        ];
+        for (bytes, escaped) in cases {
+            assert_eq!(escape(&path(bytes)), *escaped);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let cases: &[&[u8]] = &[
+            b"/etc/foo",
+            b"a\\b\\\\",
+            b"a\nb\tc\x7f\x1b",
+            "a\u{85}b".as_bytes(),
+            b"a\xffb\xc3",
+            b"\\x41\\x4",
+            b"\\",
+        ];
+        for bytes in cases {
+            let p = path(bytes);
+            assert_eq!(unescape(&escape(&p)), p, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept() {
+        assert_eq!(unescape("a\\x4"), Path::new("a\\x4"));
+        assert_eq!(unescape("a\\xzz"), Path::new("a\\xzz"));
+        assert_eq!(unescape("a\\"), Path::new("a\\"));
+        assert_eq!(unescape("a\\b"), Path::new("a\\b"));
+    }
+}
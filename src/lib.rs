@@ -0,0 +1,18 @@
+//! archdiff compares an Arch Linux system with what its packages installed &
+//! with a shadow repo of files that are tracked outside of pacman.
+
+mod cache;
+mod change;
+pub mod escape;
+mod localdb;
+pub mod mtree;
+pub mod package;
+pub mod pacman_conf;
+pub mod pacman_log;
+mod rules;
+mod scanner;
+pub mod snapshot;
+
+pub use change::{Change, Details, Drift, Status};
+pub use rules::Rule;
+pub use scanner::{relative, Config, Explanation, Scanner, MANIFEST};
@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// the files & backup entries of an installed package
+#[derive(Default)]
+pub struct Files {
+    /// paths relative to the root, directories end in a /
+    pub files: Vec<PathBuf>,
+    /// backup files with their md5
+    pub backup: Vec<(PathBuf, String)>,
+}
+
+// reads the files entry pacman keeps for every installed package. alpm only
+// hands out file names as utf-8, this keeps them as bytes.
+pub fn read_files<P: AsRef<Path>>(dbpath: P, name: &str, version: &str) -> Result<Files> {
+    let path = dbpath
+        .as_ref()
+        .join("local")
+        .join(format!("{}-{}", name, version))
+        .join("files");
+    let contents =
+        std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut files = Files::default();
+    let mut section: &[u8] = b"";
+    for line in contents.split(|b| *b == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with(b"%") && line.ends_with(b"%") {
+            section = line;
+            continue;
+        }
+        match section {
+            b"%FILES%" => files.files.push(PathBuf::from(OsStr::from_bytes(line))),
+            b"%BACKUP%" => {
+                // the hash comes after the last tab, names can't contain one
+                if let Some(tab) = line.iter().rposition(|b| *b == b'\t') {
+                    files.backup.push((
+                        PathBuf::from(OsStr::from_bytes(&line[..tab])),
+                        String::from_utf8_lossy(&line[tab + 1..]).into_owned(),
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(files)
+}
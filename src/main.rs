@@ -1,193 +1,807 @@
 use anyhow::{anyhow, Context, Result};
-use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use log::error;
-use rayon::prelude::*;
-use std::collections::{HashMap, HashSet};
-use std::fmt::Display;
+use archdiff::escape::escape;
+use archdiff::pacman_conf::PacmanConf;
+use archdiff::{mtree, relative, snapshot, Change, Config, Scanner, Status, MANIFEST};
+use similar::TextDiff;
+use std::fmt;
+use std::io::{BufRead, Write};
 use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
-use walkdir::WalkDir;
 
 #[derive(StructOpt)]
 #[structopt(name = "colaz")]
 struct Args {
-    #[structopt(long, help = "root dir", default_value = "/")]
-    root: String,
-    #[structopt(long, help = "database dir", default_value = "/var/lib/pacman")]
-    dbpath: String,
-    #[structopt(long, help = "repo dir", default_value = "/usr/share/archdiff")]
-    repo: String,
+    #[structopt(long, help = "pacman config", default_value = "/etc/pacman.conf")]
+    config: String,
+    #[structopt(
+        long,
+        help = "root dir, defaults to RootDir from the pacman config or /"
+    )]
+    root: Option<String>,
+    #[structopt(
+        long,
+        help = "database dir, defaults to DBPath from the pacman config or /var/lib/pacman"
+    )]
+    dbpath: Option<String>,
+    #[structopt(
+        long,
+        help = "repo dir, can be given more than once with later ones overriding earlier ones. \
+                %h is replaced by the hostname. defaults to /usr/share/archdiff",
+        number_of_values = 1
+    )]
+    repo: Vec<String>,
+    #[structopt(
+        long,
+        help = "hostname used for %h in repo dirs, defaults to the one of the root"
+    )]
+    hostname: Option<String>,
     #[structopt(long, help = "ignore dir", default_value = "/etc/archdiff/ignore")]
     ignore: String,
+    #[structopt(long, help = "verify package files against the local database mtree")]
+    mtree: bool,
+    #[structopt(
+        long,
+        help = "output format",
+        default_value = "text",
+        possible_values = &["text", "json", "ndjson"]
+    )]
+    format: String,
+    #[structopt(
+        long,
+        help = "print a section per group",
+        possible_values = &["package"]
+    )]
+    group_by: Option<String>,
+    #[structopt(
+        short = "0",
+        long,
+        help = "end text lines with NUL instead of newline and print paths as they are"
+    )]
+    print0: bool,
+    #[structopt(
+        long,
+        help = "statuses that result in a non-zero exit status, defaults to all",
+        use_delimiter = true
+    )]
+    fail_on: Vec<Status>,
+    #[structopt(
+        long,
+        help = "hash cache file",
+        default_value = "/var/cache/archdiff/hashes"
+    )]
+    cache: String,
+    #[structopt(long, help = "don't read or write the hash cache")]
+    no_cache: bool,
+    #[structopt(long, help = "hash all files again instead of using the hash cache")]
+    rehash: bool,
+    #[structopt(
+        short = "j",
+        long,
+        help = "number of threads to use, defaults to the number of cpus",
+        default_value = "0"
+    )]
+    threads: usize,
+    #[structopt(
+        long,
+        help = "only check for these statuses, defaults to all",
+        use_delimiter = true
+    )]
+    status: Vec<Status>,
+    #[structopt(long, help = "write the result with hashes to a file")]
+    save_snapshot: Option<String>,
+    #[structopt(long, help = "only show what changed since the snapshot in a file")]
+    since: Option<String>,
+    #[structopt(
+        long,
+        help = "only check the packages named on stdin, as given to pacman hooks with NeedsTargets"
+    )]
+    hook: bool,
+    #[structopt(
+        help = "only check these paths, defaults to the whole root",
+        parse(from_os_str)
+    )]
+    paths: Vec<PathBuf>,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-struct App {
-    alpm: alpm::Alpm,
-    ignore: Gitignore,
-    args: Args,
+#[derive(StructOpt)]
+enum Command {
+    #[structopt(about = "copy modified backup and untracked files into the repo")]
+    Sync {
+        #[structopt(short = "n", long, help = "only print what would be copied")]
+        dry_run: bool,
+        #[structopt(short = "y", long, help = "copy without asking for confirmation")]
+        yes: bool,
+    },
+    #[structopt(about = "show how a file differs from the repo or package version")]
+    Diff {
+        #[structopt(help = "file to diff", parse(from_os_str))]
+        path: PathBuf,
+    },
+    #[structopt(about = "pick an action for each difference found")]
+    Triage {
+        #[structopt(short = "n", long, help = "only print what would be written")]
+        dry_run: bool,
+    },
+    #[structopt(about = "install a pacman hook checking upgraded packages")]
+    InstallHook {
+        #[structopt(
+            long,
+            help = "hook dir, defaults to the last HookDir from the pacman config or /etc/pacman.d/hooks"
+        )]
+        dir: Option<String>,
+        #[structopt(short = "n", long, help = "only print the hook")]
+        dry_run: bool,
+    },
+    #[structopt(about = "show why a path is or isn't reported")]
+    Explain {
+        #[structopt(help = "path to explain", parse(from_os_str))]
+        path: PathBuf,
+    },
 }
 
-fn hash_file<P: AsRef<std::path::Path>>(path: P) -> Result<String> {
-    let hash = alpm::compute_md5sum(path.as_ref().as_os_str().as_bytes())
-        .map_err(|_| anyhow!("failed to hash {}", path.as_ref().display()))?;
-    Ok(hash)
+// copies src to dst in the repo and records its mode, owner & symlink target
+// in the repo's manifest
+fn copy_to_repo(src: &Path, dst: &Path, repo: &Path) -> Result<()> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory {}", parent.display()))?;
+    }
+    let meta = std::fs::symlink_metadata(src)
+        .with_context(|| format!("failed to stat {}", src.display()))?;
+    let link = if meta.file_type().is_symlink() {
+        let target = std::fs::read_link(src)
+            .with_context(|| format!("failed to read link {}", src.display()))?;
+        if std::fs::symlink_metadata(dst).is_ok() {
+            std::fs::remove_file(dst)
+                .with_context(|| format!("failed to remove {}", dst.display()))?;
+        }
+        std::os::unix::fs::symlink(&target, dst)
+            .with_context(|| format!("failed to create symlink {}", dst.display()))?;
+        Some(target)
+    } else {
+        std::fs::copy(src, dst)
+            .with_context(|| format!("failed to copy {} to {}", src.display(), dst.display()))?;
+        None
+    };
+    let path = dst.strip_prefix(repo)?;
+    mtree::update(
+        &repo.join(MANIFEST),
+        path,
+        &mtree::line(path, &meta, link.as_deref()),
+    )
 }
 
-fn hash_file_logged<P: AsRef<std::path::Path>>(path: P) -> Option<String> {
-    match hash_file(&path) {
-        Ok(hash) => Some(hash),
-        Err(err) => {
-            error!("IO error for operation on {:?}: {}", path.as_ref(), err);
-            None
+// prints a unified diff, or a single line summary for binary files. returns
+// whether the files differ.
+fn print_diff(old_name: &str, old: &[u8], new_name: &str, new: &[u8]) -> bool {
+    if old == new {
+        return false;
+    }
+    match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+        (Ok(old), Ok(new)) if !old.contains('\0') && !new.contains('\0') => print!(
+            "{}",
+            TextDiff::from_lines(old, new)
+                .unified_diff()
+                .header(old_name, new_name)
+        ),
+        _ => println!("Binary files {} and {} differ", old_name, new_name),
+    }
+    true
+}
+
+// absolute paths name files below the root, relative ones are taken from the
+// current directory and have to lead into the root
+fn root_path(root: &str, path: &Path) -> Result<PathBuf> {
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    let cwd = std::env::current_dir().context("failed to get the current directory")?;
+    let full = relative(&cwd.join(path));
+    match full.strip_prefix(relative(Path::new(root))) {
+        Ok(inside) => Ok(Path::new("/").join(inside)),
+        Err(_) => Err(anyhow!("{} is outside the root {}", path.display(), root)),
+    }
+}
+
+// escapes glob characters so a rule only matches path itself. rules are
+// text, so bytes that aren't utf-8 & control characters are matched with ?
+fn escape_rule(path: &Path) -> String {
+    let bytes = path.as_os_str().as_bytes();
+    let mut rule = String::with_capacity(bytes.len() + 1);
+    rule.push('/');
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                rule.push('\\');
+            }
+            if c.is_control() {
+                (0..c.len_utf8()).for_each(|_| rule.push('?'));
+            } else {
+                rule.push(c);
+            }
         }
+        chunk.invalid().iter().for_each(|_| rule.push('?'));
     }
+    rule
 }
 
-fn filter_map_error<Error: Display, O>(result: std::result::Result<O, Error>) -> Option<O> {
-    match result {
-        Ok(o) => Some(o),
-        Err(err) => {
-            error!("{}", err);
-            None
+// appends a line to a file, starting a new line if the file doesn't end in one
+fn append_line(path: &Path, line: &str) -> Result<()> {
+    let existing = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    if !existing.is_empty() && !existing.ends_with(b"\n") {
+        writeln!(file)?;
+    }
+    writeln!(file, "{}", line).with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
+
+// prints msg and reads a line from input, None at the end of input
+fn prompt<B: BufRead>(input: &mut std::io::Lines<B>, msg: &str) -> Result<Option<String>> {
+    print!("{}", msg);
+    std::io::stdout().flush()?;
+    Ok(input.next().transpose()?.map(|l| l.trim().to_string()))
+}
+
+// a write planned by triage
+enum Action {
+    Ignore { file: PathBuf, rule: String },
+    Copy { src: PathBuf, dst: PathBuf },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Ignore { file, rule } => write!(f, "add {} to {}", rule, file.display()),
+            Action::Copy { src, dst } => write!(f, "copy {} -> {}", escape(src), escape(dst)),
         }
     }
 }
 
-// TODO: command to sync /usr/share/archdiff automatically
+// a pacman hook running archdiff on the packages of each transaction
+fn hook_file(exe: &str) -> String {
+    format!(
+        "[Trigger]
+Operation = Install
+Operation = Upgrade
+Type = Package
+Target = *
+
+[Action]
+Description = Checking changed packages for modified files...
+When = PostTransaction
+Exec = {} --hook
+NeedsTargets
+",
+        exe
+    )
+}
+
+// the status of a change, preceded by how it drifted since a snapshot
+fn status_label(change: &Change) -> String {
+    match change.drift {
+        Some(drift) => format!("{} {}", drift.as_char(), change.status),
+        None => change.status.to_string(),
+    }
+}
+
+// the owning package of a change, with its version when known
+fn package_label(change: &Change) -> Option<String> {
+    match (&change.package, &change.version) {
+        (Some(name), Some(version)) => Some(format!("{} {}", name, version)),
+        (Some(name), None) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+// the text shown after a change, why it couldn't be checked for errors
+fn note_label(change: &Change) -> Option<&String> {
+    match change.status {
+        Status::Error => change.details.error.as_ref(),
+        _ => change.details.note.as_ref(),
+    }
+}
+
+struct App {
+    scanner: Scanner,
+    args: Args,
+    hook_dir: String,
+    // the packages given on stdin with --hook
+    targets: Vec<String>,
+}
 
 impl App {
     #[allow(clippy::new_ret_no_self)]
     fn new(mut args: Args) -> Result<Self> {
-        if !args.root.ends_with('/') {
-            args.root.push('/');
+        if args.threads > 0 {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(args.threads)
+                .build_global()?;
+        }
+        let conf = PacmanConf::parse(&args.config)?;
+        let mut root = args
+            .root
+            .take()
+            .or(conf.root_dir)
+            .unwrap_or_else(|| "/".to_string());
+        if !root.ends_with('/') {
+            root.push('/');
         }
-        if !args.repo.ends_with('/') {
-            args.repo.push('/');
+        args.paths = args
+            .paths
+            .iter()
+            .map(|p| root_path(&root, p))
+            .collect::<Result<_>>()?;
+        if let Some(Command::Diff { ref mut path }) | Some(Command::Explain { ref mut path }) =
+            args.cmd
+        {
+            *path = root_path(&root, path)?;
         }
+        let dbpath = args
+            .dbpath
+            .take()
+            .or(conf.db_path)
+            .unwrap_or_else(|| format!("{}var/lib/pacman/", root));
+        let log_file = conf
+            .log_file
+            .unwrap_or_else(|| format!("{}var/log/pacman.log", root));
+        let hook_dir = conf
+            .hook_dirs
+            .last()
+            .cloned()
+            .unwrap_or_else(|| format!("{}etc/pacman.d/hooks/", root));
+        let targets = if args.hook {
+            std::io::stdin()
+                .lock()
+                .lines()
+                .collect::<std::io::Result<Vec<_>>>()
+                .context("failed to read packages from stdin")?
+                .into_iter()
+                .map(|l| l.trim().to_string())
+                .filter(|l| !l.is_empty())
+                .collect()
+        } else {
+            vec![]
+        };
+        let cache_dirs = if conf.cache_dirs.is_empty() {
+            vec![format!("{}var/cache/pacman/pkg/", root)]
+        } else {
+            conf.cache_dirs
+        };
+        let scanner = Scanner::new(Config {
+            root,
+            dbpath,
+            log_file,
+            cache_dirs,
+            repos: if args.repo.is_empty() {
+                vec!["/usr/share/archdiff".to_string()]
+            } else {
+                args.repo.clone()
+            },
+            hostname: args.hostname.clone(),
+            ignore: args.ignore.clone(),
+            no_extract: conf.no_extract,
+            no_upgrade: conf.no_upgrade,
+            mtree: args.mtree,
+            paths: args.paths.clone(),
+            packages: targets.clone(),
+            statuses: args.status.clone(),
+            cache: if args.no_cache {
+                None
+            } else {
+                Some(args.cache.clone())
+            },
+            rehash: args.rehash,
+            hash_untracked: args.save_snapshot.is_some() || args.since.is_some(),
+            threads: args.threads,
+        })?;
         Ok(Self {
-            alpm: alpm::Alpm::new(args.root.as_bytes(), args.dbpath.as_bytes())?,
-            ignore: Self::build_gitignore(&args.ignore)?,
+            scanner,
             args,
+            hook_dir,
+            targets,
         })
     }
 
-    fn build_gitignore(ignore: &str) -> Result<Gitignore> {
-        let mut gi_builder = GitignoreBuilder::new("/");
-        let ignores = std::fs::read_dir(ignore)
-            .with_context(|| format!("failed to read directory {}", ignore))?;
-        for path in ignores {
-            let path = path?;
-            let oerr = gi_builder.add(path.path());
-            if let Some(err) = oerr {
-                return Err(err.into());
+    fn print(&self, mut changes: Vec<Change>) -> Result<()> {
+        let grouped = self.args.group_by.is_some();
+        if grouped {
+            // unowned files go last, the sort is stable so paths stay sorted
+            changes.sort_by(|a, b| {
+                (a.package.is_none(), &a.package).cmp(&(b.package.is_none(), &b.package))
+            });
+        }
+        let errors = changes.iter().filter(|c| c.status == Status::Error).count();
+        let root = self.scanner.root();
+        let changes = changes.into_iter().map(|c| Change {
+            path: Path::new(root).join(&c.path),
+            ..c
+        });
+        match self.args.format.as_str() {
+            "json" => println!("{}", serde_json::to_string(&changes.collect::<Vec<_>>())?),
+            "ndjson" => {
+                for c in changes {
+                    println!("{}", serde_json::to_string(&c)?);
+                }
             }
+            _ if self.args.print0 => {
+                let mut out = std::io::stdout();
+                for c in changes {
+                    write!(out, "{} ", status_label(&c))?;
+                    out.write_all(c.path.as_os_str().as_bytes())?;
+                    out.write_all(b"\0")?;
+                }
+            }
+            _ if grouped => {
+                let mut section = None;
+                for c in changes {
+                    let label = package_label(&c);
+                    if section.as_ref() != Some(&label) {
+                        if section.is_some() {
+                            println!();
+                        }
+                        println!(":: {}", label.as_deref().unwrap_or("no package"));
+                        section = Some(label);
+                    }
+                    let path = escape(&c.path);
+                    match note_label(&c) {
+                        Some(note) => println!("{} {}: {}", status_label(&c), path, note),
+                        None => println!("{} {}", status_label(&c), path),
+                    }
+                }
+            }
+            _ => changes.for_each(|c| {
+                let path = escape(&c.path);
+                let line = match package_label(&c) {
+                    Some(label) => format!("{} {} ({})", status_label(&c), path, label),
+                    None => format!("{} {}", status_label(&c), path),
+                };
+                match note_label(&c) {
+                    Some(note) => println!("{}: {}", line, note),
+                    None => println!("{}", line),
+                }
+            }),
         }
-        Ok(gi_builder.build()?)
+        if errors > 0 {
+            eprintln!("{} paths could not be checked", errors);
+        }
+        Ok(())
     }
 
-    fn run(&self) {
-        let mut pkg_files = HashSet::new();
-        let mut pkg_backup_files = HashMap::new();
-        for pkg in self.alpm.localdb().pkgs() {
-            pkg_files.extend(pkg.files().files().iter().map(|f| f.name().to_string()));
-            pkg_backup_files.extend(
-                pkg.backup()
+    // returns whether differences were found
+    fn run(&self) -> Result<bool> {
+        match self.args.cmd {
+            // differences are reported but never fail the transaction
+            None if self.args.hook => {
+                if !self.targets.is_empty() {
+                    self.print(self.scanner.scan().collect())?;
+                }
+                Ok(false)
+            }
+            None => {
+                // read before saving, both may be the same file
+                let previous = match &self.args.since {
+                    Some(path) => Some(snapshot::load(path)?),
+                    None => None,
+                };
+                let mut changes: Vec<_> = self.scanner.scan().collect();
+                if let Some(path) = &self.args.save_snapshot {
+                    snapshot::save(path, &changes)?;
+                }
+                if let Some(previous) = previous {
+                    changes = snapshot::since(previous, changes);
+                }
+                let fail_on = &self.args.fail_on;
+                let failed = changes
                     .iter()
-                    .map(|b| (b.name().to_string(), b.hash().to_string())),
-            );
+                    .any(|c| fail_on.is_empty() || fail_on.contains(&c.status));
+                self.print(changes)?;
+                Ok(failed)
+            }
+            Some(Command::Sync { dry_run, yes }) => self.sync(dry_run, yes).map(|_| false),
+            Some(Command::Diff { ref path }) => self.diff(path),
+            Some(Command::InstallHook { ref dir, dry_run }) => self
+                .install_hook(dir.as_deref().unwrap_or(&self.hook_dir), dry_run)
+                .map(|_| false),
+            Some(Command::Triage { dry_run }) => self.triage(dry_run).map(|_| false),
+            Some(Command::Explain { ref path }) => self.explain(path).map(|_| false),
         }
+    }
 
-        let root = &self.args.root;
-        let ignored = &self.ignore;
-        let root_len = self.args.root.len();
-        let repo_len = self.args.repo.len();
-
-        let mut all = vec![];
-
-        // untracked files on disk
-        WalkDir::new(&self.args.root)
-            .into_iter()
-            .filter_entry(|de| {
-                self.ignore
-                    .matched(de.path(), de.file_type().is_dir())
-                    .is_none()
-            })
-            .filter_map(filter_map_error)
-            .for_each(|de| {
-                if de.file_type().is_dir() {
-                    return;
-                }
-                let path = &de.path().to_string_lossy()[root_len..];
-                let removed = pkg_files.remove(path);
-                if !removed {
-                    all.push(('?', path.to_string()));
-                }
-            });
+    // the repo layer files are copied into, the topmost one
+    fn top_repo(&self) -> Result<&Path> {
+        self.scanner
+            .repos()
+            .last()
+            .map(Path::new)
+            .ok_or_else(|| anyhow!("no repo to copy files into"))
+    }
 
-        // repo files that have been changed
-        WalkDir::new(&self.args.repo)
-            .into_iter()
-            .filter_map(filter_map_error)
-            .for_each(|de| {
-                if de.file_type().is_dir() {
-                    return;
-                }
-                let path = &de.path().to_string_lossy()[repo_len..];
-                pkg_backup_files.remove(path);
-                let repo_hash = match hash_file_logged(de.path()) {
-                    None => return,
-                    Some(h) => h,
-                };
-                let actual_hash = match hash_file_logged(&format!("{}{}", &root, path)) {
-                    None => return,
-                    Some(h) => h,
-                };
-                if repo_hash != actual_hash {
-                    all.push(('R', path.to_string()));
-                }
-            });
+    // copies the live version of modified backup files and untracked files
+    // into the repo, so they are tracked from then on
+    fn sync(&self, dry_run: bool, yes: bool) -> Result<()> {
+        let root = Path::new(self.scanner.root());
+        let repo = self.top_repo()?;
+        let plan: Vec<_> = self
+            .scanner
+            .scan()
+            .filter(|c| c.status == Status::Backup || c.status == Status::Untracked)
+            .map(|c| (root.join(&c.path), repo.join(&c.path)))
+            .collect();
+        plan.iter()
+            .for_each(|(src, dst)| println!("{} -> {}", escape(src), escape(dst)));
+        if dry_run || plan.is_empty() {
+            return Ok(());
+        }
+        // a missing ignore rule would copy whole trees, so ask first
+        if !yes {
+            let stdin = std::io::stdin();
+            let mut input = stdin.lock().lines();
+            let answer = prompt(&mut input, &format!("copy {} files? [y/N] ", plan.len()))?;
+            if !matches!(answer.as_deref(), Some("y") | Some("yes")) {
+                return Ok(());
+            }
+        }
+        for (src, dst) in &plan {
+            copy_to_repo(src, dst, repo)?;
+        }
+        Ok(())
+    }
 
-        // deleted files from packages
-        all.par_extend(pkg_files.into_par_iter().filter_map(|p| {
-            let fp = format!("{}{}", &root, &p);
-            if ignored.matched(&fp, false).is_ignore() {
-                None
+    // shows how a file differs from its repo copy, or from the version shipped
+    // in the package if it isn't tracked in the repo
+    fn diff(&self, path: &Path) -> Result<bool> {
+        let (original_name, original) = self.scanner.original(path)?;
+        let live_path = Path::new(self.scanner.root()).join(relative(path));
+        let live = std::fs::read(&live_path)
+            .with_context(|| format!("failed to read {}", live_path.display()))?;
+        Ok(print_diff(
+            &original_name,
+            &original,
+            &escape(&live_path),
+            &live,
+        ))
+    }
+
+    fn install_hook(&self, dir: &str, dry_run: bool) -> Result<()> {
+        let exe = std::env::current_exe().context("failed to find the archdiff executable")?;
+        let hook = hook_file(&exe.to_string_lossy());
+        let path = Path::new(dir).join("archdiff.hook");
+        println!("{}:\n{}", path.display(), hook);
+        if dry_run {
+            return Ok(());
+        }
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory {}", dir))?;
+        std::fs::write(&path, hook).with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    // asks for an action for every change, then shows the planned writes and
+    // only applies them once confirmed
+    fn triage(&self, dry_run: bool) -> Result<()> {
+        let root = Path::new(self.scanner.root());
+        let repo = self.top_repo()?;
+        let changes: Vec<_> = self.scanner.scan().collect();
+        let stdin = std::io::stdin();
+        let mut input = stdin.lock().lines();
+        let mut plan = vec![];
+        let mut ignore_file = None;
+        'changes: for (n, c) in changes.iter().enumerate() {
+            let path = root.join(&c.path);
+            match package_label(c) {
+                Some(label) => println!(
+                    "[{}/{}] {} {} ({})",
+                    n + 1,
+                    changes.len(),
+                    c.status,
+                    escape(&path),
+                    label
+                ),
+                None => println!(
+                    "[{}/{}] {} {}",
+                    n + 1,
+                    changes.len(),
+                    c.status,
+                    escape(&path)
+                ),
+            }
+            // only files that exist on the system can be copied
+            let copyable = matches!(c.status, Status::Untracked | Status::Backup | Status::Repo);
+            let choices = if copyable {
+                "[i]gnore, [c]opy to repo, [d]iff, [s]kip, [q]uit? "
             } else {
-                match std::fs::metadata(&fp).with_context(|| format!("failed to stat {}", fp)) {
-                    Err(_) => Some(('D', p)),
-                    Ok(_) => None,
+                "[i]gnore, [d]iff, [s]kip, [q]uit? "
+            };
+            loop {
+                match prompt(&mut input, choices)?.as_deref() {
+                    Some("i") => {
+                        let file = match self.choose_ignore_file(&mut input, &ignore_file)? {
+                            Some(file) => file,
+                            None => break 'changes,
+                        };
+                        plan.push(Action::Ignore {
+                            file: file.clone(),
+                            rule: format!("[{}] {}", c.status, escape_rule(&c.path)),
+                        });
+                        ignore_file = Some(file);
+                        break;
+                    }
+                    Some("c") if copyable => {
+                        plan.push(Action::Copy {
+                            src: path.clone(),
+                            dst: repo.join(&c.path),
+                        });
+                        break;
+                    }
+                    Some("d") => {
+                        if let Err(err) = self.diff(&c.path) {
+                            eprintln!("Error: {:#}", err);
+                        }
+                    }
+                    Some("s") => break,
+                    Some("q") | None => break 'changes,
+                    Some(_) => println!("unknown action"),
                 }
             }
-        }));
-
-        // backup files that have been changed
-        all.par_extend(
-            pkg_backup_files
-                .into_par_iter()
-                .filter_map(|(p, expected_hash)| {
-                    let fp = format!("{}{}", &root, &p);
-                    if ignored.matched_path_or_any_parents(&fp, false).is_ignore() {
-                        None
-                    } else {
-                        hash_file_logged(&fp).and_then(|actual_hash| {
-                            if expected_hash == actual_hash {
-                                None
-                            } else {
-                                Some(('B', p))
-                            }
-                        })
-                    }
-                }),
-        );
+        }
 
-        all.sort_by(|(_, a), (_, b)| a.cmp(b));
-        all.iter()
-            .for_each(|(c, n)| println!("{} {}{}", c, &root, n));
+        if plan.is_empty() {
+            println!("nothing to do");
+            return Ok(());
+        }
+        println!();
+        plan.iter().for_each(|a| println!("{}", a));
+        if dry_run {
+            return Ok(());
+        }
+        let answer = prompt(&mut input, &format!("apply {} changes? [y/N] ", plan.len()))?;
+        if !matches!(answer.as_deref(), Some("y") | Some("yes")) {
+            return Ok(());
+        }
+        for action in &plan {
+            match action {
+                Action::Ignore { file, rule } => append_line(file, rule)?,
+                Action::Copy { src, dst } => copy_to_repo(src, dst, repo)?,
+            }
+        }
+        Ok(())
+    }
+
+    // asks for a file in the ignore dir by number or name, None at the end of
+    // input
+    fn choose_ignore_file<B: BufRead>(
+        &self,
+        input: &mut std::io::Lines<B>,
+        default: &Option<PathBuf>,
+    ) -> Result<Option<PathBuf>> {
+        let dir = Path::new(&self.args.ignore);
+        let mut files = std::fs::read_dir(dir)
+            .with_context(|| format!("failed to read directory {}", dir.display()))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        files.sort();
+        for (n, file) in files.iter().enumerate() {
+            println!("{}) {}", n + 1, file.display());
+        }
+        let msg = match default {
+            Some(file) => format!("ignore file, number or new name [{}]: ", file.display()),
+            None => "ignore file, number or new name: ".to_string(),
+        };
+        loop {
+            let answer = match prompt(input, &msg)? {
+                Some(answer) => answer,
+                None => return Ok(None),
+            };
+            match answer.parse::<usize>() {
+                _ if answer.is_empty() && default.is_some() => return Ok(default.clone()),
+                Ok(n) if n >= 1 && n <= files.len() => return Ok(Some(files[n - 1].clone())),
+                Err(_) if !answer.is_empty() && !answer.contains('/') => {
+                    return Ok(Some(dir.join(answer)))
+                }
+                _ => println!("no such ignore file"),
+            }
+        }
+    }
+
+    fn explain(&self, path: &Path) -> Result<()> {
+        let e = self.scanner.explain(path)?;
+        println!(
+            "{}",
+            escape(&Path::new(self.scanner.root()).join(relative(path)))
+        );
+        if e.packages.is_empty() {
+            println!("not owned by any package");
+        }
+        for (name, version) in &e.packages {
+            println!("owned by {} {}", name, version);
+        }
+        for (name, expected) in &e.backup {
+            println!(
+                "checked like a backup file of {}: expected md5 {}, actual md5 {}",
+                name,
+                expected,
+                e.actual_hash.as_deref().unwrap_or("unknown")
+            );
+        }
+        if e.no_extract {
+            println!("matches NoExtract");
+        }
+        if e.no_upgrade {
+            println!("matches NoUpgrade");
+        }
+        match &e.repo {
+            Some(repo) => println!("tracked in the repo as {}", escape(repo)),
+            None => println!("not tracked in the repo"),
+        }
+        if let Some(tombstone) = &e.tombstone {
+            println!("marked as deleted by {}", escape(tombstone));
+        }
+        if let Some(entry) = &e.manifest {
+            let mut attributes = vec![];
+            if let Some(mode) = entry.mode {
+                attributes.push(format!("mode {:04o}", mode));
+            }
+            if let Some(uid) = entry.uid {
+                attributes.push(format!("uid {}", uid));
+            }
+            if let Some(gid) = entry.gid {
+                attributes.push(format!("gid {}", gid));
+            }
+            if let Some(link) = &entry.link {
+                attributes.push(format!("link {}", escape(link)));
+            }
+            println!("expected by the repo manifest: {}", attributes.join(", "));
+        }
+        if e.rules.is_empty() {
+            println!("no ignore rules match");
+        }
+        for (status, rule) in &e.rules {
+            println!(
+                "{} {} by {}:{}: {}",
+                status,
+                if rule.whitelist {
+                    "not ignored"
+                } else {
+                    "ignored"
+                },
+                rule.file.display(),
+                rule.line,
+                rule.rule
+            );
+        }
+        Ok(())
     }
 }
 
-fn main() -> Result<()> {
+// exits like diff: 0 without differences, 1 with differences and 2 on errors
+fn main() {
     pretty_env_logger::init();
-    App::new(Args::from_args())?.run();
-    Ok(())
+    let args = match Args::from_iter_safe(std::env::args_os()) {
+        Ok(args) => args,
+        Err(err) if err.use_stderr() => {
+            eprintln!("{}", err.message);
+            std::process::exit(2);
+        }
+        Err(err) => err.exit(),
+    };
+    let code = match App::new(args).and_then(|app| app.run()) {
+        Ok(false) => 0,
+        Ok(true) => 1,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            2
+        }
+    };
+    std::process::exit(code);
 }
@@ -0,0 +1,333 @@
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    File,
+    Dir,
+    Link,
+}
+
+// a single path from an mtree file, with the /set defaults applied
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub kind: Kind,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub md5: Option<String>,
+    pub sha256: Option<String>,
+    pub link: Option<PathBuf>,
+}
+
+#[derive(Clone, Default)]
+struct Keywords {
+    kind: Option<Kind>,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    md5: Option<String>,
+    sha256: Option<String>,
+    link: Option<PathBuf>,
+}
+
+impl Keywords {
+    fn set(&mut self, keyword: &str) {
+        let (key, value) = match keyword.find('=') {
+            Some(i) => (&keyword[..i], &keyword[i + 1..]),
+            None => (keyword, ""),
+        };
+        match key {
+            "type" => {
+                self.kind = match value {
+                    "file" => Some(Kind::File),
+                    "dir" => Some(Kind::Dir),
+                    "link" => Some(Kind::Link),
+                    _ => None,
+                }
+            }
+            "mode" => self.mode = u32::from_str_radix(value, 8).ok(),
+            "uid" => self.uid = value.parse().ok(),
+            "gid" => self.gid = value.parse().ok(),
+            "size" => self.size = value.parse().ok(),
+            "md5digest" => self.md5 = Some(value.to_string()),
+            "sha256digest" => self.sha256 = Some(value.to_string()),
+            "link" => self.link = Some(unescape(value)),
+            _ => (),
+        }
+    }
+
+    fn unset(&mut self, key: &str) {
+        match key {
+            "type" => self.kind = None,
+            "mode" => self.mode = None,
+            "uid" => self.uid = None,
+            "gid" => self.gid = None,
+            "size" => self.size = None,
+            "md5digest" => self.md5 = None,
+            "sha256digest" => self.sha256 = None,
+            "link" => self.link = None,
+            "all" => *self = Keywords::default(),
+            _ => (),
+        }
+    }
+}
+
+// mtree escapes special characters in names as \ followed by three octal digits
+fn unescape(s: &str) -> PathBuf {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(digits, 8) {
+                out.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    PathBuf::from(OsString::from_vec(out))
+}
+
+// the reverse of unescape, for writing names & link targets
+fn escape(path: &Path) -> String {
+    let mut out = String::new();
+    for &b in path.as_os_str().as_bytes() {
+        if b.is_ascii_graphic() && b != b'\\' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("\\{:03o}", b));
+        }
+    }
+    out
+}
+
+/// the line describing a file on disk: its type, mode, owner & symlink
+/// target. meta is expected to come from symlink_metadata.
+pub fn line(path: &Path, meta: &std::fs::Metadata, link: Option<&Path>) -> String {
+    let kind = match link {
+        Some(target) => format!("type=link link={}", escape(target)),
+        None => "type=file".to_string(),
+    };
+    format!(
+        "./{} {} mode={:04o} uid={} gid={}",
+        escape(path),
+        kind,
+        meta.mode() & 0o7777,
+        meta.uid(),
+        meta.gid()
+    )
+}
+
+/// sets the line of path in the mtree file at manifest, in place of the
+/// lines describing it so far. the file is created if it doesn't exist.
+pub fn update(manifest: &Path, path: &Path, line: &str) -> Result<()> {
+    let contents = match std::fs::read_to_string(manifest) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", manifest.display()))
+        }
+    };
+    let mut lines = vec![];
+    let mut replaced = false;
+    for l in contents.lines() {
+        let describes = match l.split_whitespace().next() {
+            None | Some("/set") | Some("/unset") => false,
+            Some(w) if w.starts_with('#') => false,
+            Some(name) => {
+                let name = unescape(name);
+                name.strip_prefix("./").unwrap_or(&name) == path
+            }
+        };
+        if !describes {
+            lines.push(l);
+        } else if !replaced {
+            lines.push(line);
+            replaced = true;
+        }
+    }
+    if !replaced {
+        lines.push(line);
+    }
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    std::fs::write(manifest, contents)
+        .with_context(|| format!("failed to write {}", manifest.display()))
+}
+
+pub fn parse<R: BufRead>(reader: R) -> Result<Vec<Entry>> {
+    let mut defaults = Keywords::default();
+    let mut entries = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let first = match words.next() {
+            None => continue,
+            Some(w) if w.starts_with('#') => continue,
+            Some(w) => w,
+        };
+        match first {
+            "/set" => words.for_each(|w| defaults.set(w)),
+            "/unset" => words.for_each(|w| defaults.unset(w)),
+            name => {
+                let mut keywords = defaults.clone();
+                words.for_each(|w| keywords.set(w));
+                let path = unescape(name);
+                let path = path.strip_prefix("./").unwrap_or(&path).to_path_buf();
+                entries.push(Entry {
+                    path,
+                    kind: keywords.kind.unwrap_or(Kind::File),
+                    mode: keywords.mode,
+                    uid: keywords.uid,
+                    gid: keywords.gid,
+                    size: keywords.size,
+                    md5: keywords.md5,
+                    sha256: keywords.sha256,
+                    link: keywords.link,
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// reads a plain text mtree file
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>> {
+    let path = path.as_ref();
+    let file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    parse(BufReader::new(file)).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+// reads the gzipped mtree pacman keeps for every installed package
+pub fn read_local<P: AsRef<Path>>(dbpath: P, name: &str, version: &str) -> Result<Vec<Entry>> {
+    let path = dbpath
+        .as_ref()
+        .join("local")
+        .join(format!("{}-{}", name, version))
+        .join("mtree");
+    let file =
+        std::fs::File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+    parse(BufReader::new(GzDecoder::new(file)))
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_unset() {
+        let mtree = "#mtree
+/set type=file uid=0 gid=0 mode=644
+./.PKGINFO time=1 size=10
+./etc time=1 mode=755 type=dir
+./etc/foo time=1 size=3 md5digest=abc sha256digest=def
+/unset uid
+./etc/bar type=link link=foo
+/unset all
+./etc/baz
+";
+        let entries = parse(mtree.as_bytes()).unwrap();
+        let paths: Vec<_> = entries.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(
+            paths,
+            [".PKGINFO", "etc", "etc/foo", "etc/bar", "etc/baz"]
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>()
+        );
+
+        let pkginfo = &entries[0];
+        assert_eq!(pkginfo.kind, Kind::File);
+        assert_eq!(pkginfo.mode, Some(0o644));
+        assert_eq!(pkginfo.size, Some(10));
+
+        let etc = &entries[1];
+        assert_eq!(etc.kind, Kind::Dir);
+        assert_eq!(etc.mode, Some(0o755));
+        assert_eq!(etc.uid, Some(0));
+
+        let foo = &entries[2];
+        assert_eq!(foo.md5.as_deref(), Some("abc"));
+        assert_eq!(foo.sha256.as_deref(), Some("def"));
+
+        let bar = &entries[3];
+        assert_eq!(bar.kind, Kind::Link);
+        assert_eq!(bar.link.as_deref(), Some(Path::new("foo")));
+        assert_eq!(bar.uid, None);
+        assert_eq!(bar.gid, Some(0));
+        assert_eq!(bar.mode, Some(0o644));
+
+        let baz = &entries[4];
+        assert_eq!(baz.kind, Kind::File);
+        assert_eq!((baz.mode, baz.uid, baz.gid), (None, None, None));
+    }
+
+    #[test]
+    fn octal_escapes() {
+        let entries = parse(&b"./usr/a\\040b type=link link=c\\040d\n./usr/\\377\n"[..]).unwrap();
+        assert_eq!(entries[0].path, Path::new("usr/a b"));
+        assert_eq!(entries[0].link.as_deref(), Some(Path::new("c d")));
+        assert_eq!(entries[1].path.as_os_str().as_bytes(), b"usr/\xff");
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept() {
+        assert_eq!(unescape("a\\04"), Path::new("a\\04"));
+        assert_eq!(unescape("a\\9zz"), Path::new("a\\9zz"));
+    }
+
+    #[test]
+    fn leading_dot_is_stripped() {
+        let entries = parse(&b"./a/./b\n.\n/abs\n"[..]).unwrap();
+        assert_eq!(entries[0].path, Path::new("a/./b"));
+        assert_eq!(entries[1].path, Path::new(""));
+        assert_eq!(entries[2].path, Path::new("/abs"));
+    }
+
+    #[test]
+    fn lines_round_trip() {
+        let meta = std::fs::symlink_metadata(std::env::temp_dir()).unwrap();
+        let name = PathBuf::from(OsString::from_vec(b"etc/a b\\\xff".to_vec()));
+        let line = line(&name, &meta, Some(Path::new("c d")));
+        let entries = parse(line.as_bytes()).unwrap();
+        assert_eq!(entries[0].path, name);
+        assert_eq!(entries[0].kind, Kind::Link);
+        assert_eq!(entries[0].link.as_deref(), Some(Path::new("c d")));
+        assert_eq!(entries[0].mode, Some(meta.mode() & 0o7777));
+        assert_eq!(entries[0].uid, Some(meta.uid()));
+        assert_eq!(entries[0].gid, Some(meta.gid()));
+    }
+
+    #[test]
+    fn update_replaces_entries() {
+        let manifest = std::env::temp_dir().join(format!("archdiff-{}.mtree", std::process::id()));
+        std::fs::write(
+            &manifest,
+            "/set uid=0\n./etc/a mode=0600\n./etc/b mode=0644\netc/a mode=0640",
+        )
+        .unwrap();
+        update(&manifest, Path::new("etc/a"), "./etc/a mode=0400").unwrap();
+        update(&manifest, Path::new("etc/c"), "./etc/c mode=0755").unwrap();
+        let contents = std::fs::read_to_string(&manifest).unwrap();
+        std::fs::remove_file(&manifest).unwrap();
+        assert_eq!(
+            contents,
+            "/set uid=0\n./etc/a mode=0400\n./etc/b mode=0644\n./etc/c mode=0755\n"
+        );
+    }
+}
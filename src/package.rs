@@ -0,0 +1,58 @@
+use anyhow::{anyhow, Context, Result};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// finds the package file for the given package in one of the cache dirs
+pub fn find_cached(
+    cache_dirs: &[String],
+    name: &str,
+    version: &str,
+    arch: &str,
+) -> Option<PathBuf> {
+    let prefix = format!("{}-{}-{}.pkg.tar", name, version, arch);
+    cache_dirs
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|de| de.ok())
+        .map(|de| de.path())
+        .find(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(&prefix) && !n.ends_with(".sig"))
+        })
+}
+
+fn decoder(path: &Path) -> Result<Box<dyn Read>> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let name = path.to_string_lossy();
+    Ok(if name.ends_with(".zst") {
+        Box::new(zstd::Decoder::new(file)?)
+    } else if name.ends_with(".xz") {
+        Box::new(xz2::read::XzDecoder::new(file))
+    } else if name.ends_with(".gz") {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else if name.ends_with(".tar") {
+        Box::new(file)
+    } else {
+        return Err(anyhow!("unsupported compression for {}", path.display()));
+    })
+}
+
+// reads the contents of a single file from a package file
+pub fn extract(pkg: &Path, path: &Path) -> Result<Vec<u8>> {
+    let mut archive = tar::Archive::new(decoder(pkg)?);
+    for entry in archive
+        .entries()
+        .with_context(|| format!("failed to read {}", pkg.display()))?
+    {
+        let mut entry = entry?;
+        if entry.path()?.as_ref() == path {
+            let mut contents = vec![];
+            entry.read_to_end(&mut contents)?;
+            return Ok(contents);
+        }
+    }
+    Err(anyhow!("{} not found in {}", path.display(), pkg.display()))
+}
@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Context, Result};
+use globset::{Glob, GlobMatcher};
+use std::path::Path;
+
+// pacman gives up on Include chains deeper than this
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+// the subset of pacman.conf archdiff cares about
+#[derive(Default)]
+pub struct PacmanConf {
+    pub root_dir: Option<String>,
+    pub db_path: Option<String>,
+    pub log_file: Option<String>,
+    pub cache_dirs: Vec<String>,
+    pub hook_dirs: Vec<String>,
+    pub no_extract: Vec<String>,
+    pub no_upgrade: Vec<String>,
+}
+
+impl PacmanConf {
+    pub fn parse<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut conf = Self::default();
+        let mut section = String::new();
+        conf.parse_file(path.as_ref(), &mut section, 0)?;
+        Ok(conf)
+    }
+
+    fn parse_file(&mut self, path: &Path, section: &mut String, depth: usize) -> Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(anyhow!("too many levels of Include at {}", path.display()));
+        }
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        for line in contents.lines() {
+            let line = match line.find('#') {
+                Some(i) => &line[..i],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                *section = line[1..line.len() - 1].to_string();
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => (line, ""),
+            };
+            if key == "Include" {
+                self.include(value, section, depth)
+                    .with_context(|| format!("failed to include {}", value))?;
+                continue;
+            }
+            if section != "options" {
+                continue;
+            }
+            match key {
+                "RootDir" => self.root_dir = Some(value.to_string()),
+                "DBPath" => self.db_path = Some(value.to_string()),
+                "LogFile" => self.log_file = Some(value.to_string()),
+                "CacheDir" => self.cache_dirs.push(value.to_string()),
+                "HookDir" => self.hook_dirs.push(value.to_string()),
+                "NoExtract" => self
+                    .no_extract
+                    .extend(value.split_whitespace().map(String::from)),
+                "NoUpgrade" => self
+                    .no_upgrade
+                    .extend(value.split_whitespace().map(String::from)),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn include(&mut self, pattern: &str, section: &mut String, depth: usize) -> Result<()> {
+        for path in glob::glob(pattern)? {
+            self.parse_file(&path?, section, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+// NoExtract & NoUpgrade style patterns. like pacman, the last matching
+// pattern wins and a leading ! negates it.
+pub struct Patterns(Vec<(bool, GlobMatcher)>);
+
+impl Patterns {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let mut compiled = vec![];
+        for pattern in patterns {
+            let (negated, pattern) = match pattern.strip_prefix('!') {
+                Some(p) => (true, p),
+                None => (false, pattern.as_str()),
+            };
+            let glob =
+                Glob::new(pattern).with_context(|| format!("invalid pattern {}", pattern))?;
+            compiled.push((negated, glob.compile_matcher()));
+        }
+        Ok(Self(compiled))
+    }
+
+    pub fn is_match<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = path.as_ref();
+        self.0
+            .iter()
+            .rev()
+            .find(|(_, glob)| glob.is_match(path))
+            .is_some_and(|(negated, _)| !negated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // a fresh directory for a test's config files
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "archdiff-pacman-conf-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn options_and_include() {
+        let dir = test_dir("include");
+        std::fs::create_dir(dir.join("conf.d")).unwrap();
+        std::fs::write(
+            dir.join("conf.d/extra.conf"),
+            "NoUpgrade = etc/a etc/b\nCacheDir = /extra/\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("pacman.conf"),
+            format!(
+                "# comment
+[options]
+RootDir = /mnt # trailing comment
+CacheDir = /cache/
+NoExtract = usr/share/doc/* !usr/share/doc/pacman/*
+Include = {}/conf.d/*.conf
+LogFile = /var/log/other.log
+[core]
+DBPath = /ignored
+HookDir = /ignored
+",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        let conf = PacmanConf::parse(dir.join("pacman.conf")).unwrap();
+        assert_eq!(conf.root_dir.as_deref(), Some("/mnt"));
+        assert_eq!(conf.db_path, None);
+        assert_eq!(conf.log_file.as_deref(), Some("/var/log/other.log"));
+        assert_eq!(conf.cache_dirs, ["/cache/", "/extra/"]);
+        assert!(conf.hook_dirs.is_empty());
+        assert_eq!(
+            conf.no_extract,
+            ["usr/share/doc/*", "!usr/share/doc/pacman/*"]
+        );
+        assert_eq!(conf.no_upgrade, ["etc/a", "etc/b"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_loop_fails() {
+        let dir = test_dir("loop");
+        let path = dir.join("pacman.conf");
+        std::fs::write(&path, format!("[options]\nInclude = {}\n", path.display())).unwrap();
+        assert!(PacmanConf::parse(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn patterns(patterns: &[&str]) -> Patterns {
+        let patterns: Vec<_> = patterns.iter().map(|p| p.to_string()).collect();
+        Patterns::new(&patterns).unwrap()
+    }
+
+    #[test]
+    fn negated_pattern() {
+        let p = patterns(&["usr/share/locale/*", "!usr/share/locale/en*"]);
+        assert!(p.is_match("usr/share/locale/de/LC_MESSAGES/foo.mo"));
+        assert!(!p.is_match("usr/share/locale/en_US/LC_MESSAGES/foo.mo"));
+        assert!(!p.is_match("etc/foo"));
+    }
+
+    #[test]
+    fn last_match_wins() {
+        let p = patterns(&["!etc/a", "etc/*"]);
+        assert!(p.is_match("etc/a"));
+        let p = patterns(&["etc/*", "!etc/a"]);
+        assert!(!p.is_match("etc/a"));
+        assert!(p.is_match("etc/b"));
+    }
+}
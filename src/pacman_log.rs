@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::BufRead;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+// the package name & version of an action line like
+// "[2021-01-01T10:00:00+0100] [ALPM] removed foo (1.0-1)". for upgrades and
+// downgrades the version before the transaction is returned.
+fn parse_action(line: &str) -> Option<(String, String)> {
+    let rest = line.split("] [ALPM] ").nth(1)?;
+    let (action, rest) = rest.split_once(' ')?;
+    if !matches!(
+        action,
+        "removed" | "upgraded" | "downgraded" | "reinstalled"
+    ) {
+        return None;
+    }
+    let (name, version) = rest.split_once(" (")?;
+    let version = version.trim_end().strip_suffix(')')?;
+    let version = version.split(" -> ").next()?;
+    Some((name.to_string(), version.to_string()))
+}
+
+/// maps every file pacman saved as a .pacsave to the packages that were
+/// removed or upgraded each time, oldest first, according to the pacman log.
+/// paths are made relative to root.
+pub fn saved_files<P: AsRef<Path>>(
+    path: P,
+    root: &str,
+) -> Result<HashMap<PathBuf, Vec<(String, String)>>> {
+    let path = path.as_ref();
+    let file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut saved = HashMap::new();
+    // pacman logs the warning right before the action it belongs to
+    let mut pending = vec![];
+    for line in std::io::BufReader::new(file).split(b'\n') {
+        let line = line.with_context(|| format!("failed to read {}", path.display()))?;
+        // file names are kept as bytes, the rest of the line is text
+        const SAVED_AS: &[u8] = b" saved as ";
+        if let Some(i) = line.windows(SAVED_AS.len()).position(|w| w == SAVED_AS) {
+            let new = line[i + SAVED_AS.len()..].trim_ascii_end();
+            let new = new.strip_prefix(root.as_bytes()).unwrap_or(new);
+            let new = Path::new(OsStr::from_bytes(new));
+            pending.push(new.strip_prefix("/").unwrap_or(new).to_path_buf());
+        } else if let Some(pkg) = parse_action(&String::from_utf8_lossy(&line)) {
+            for p in pending.drain(..) {
+                saved.entry(p).or_insert_with(Vec::new).push(pkg.clone());
+            }
+        }
+    }
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(name: &str, version: &str) -> Option<(String, String)> {
+        Some((name.to_string(), version.to_string()))
+    }
+
+    #[test]
+    fn actions() {
+        assert_eq!(
+            parse_action("[2021-01-01T10:00:00+0100] [ALPM] removed foo (1.0-1)"),
+            action("foo", "1.0-1")
+        );
+        assert_eq!(
+            parse_action("[2021-01-01T10:00:00+0100] [ALPM] upgraded foo (1.0-1 -> 1.1-1)"),
+            action("foo", "1.0-1")
+        );
+        assert_eq!(
+            parse_action("[2021-01-01T10:00:00+0100] [ALPM] downgraded foo (1.1-1 -> 1.0-1)"),
+            action("foo", "1.1-1")
+        );
+        assert_eq!(
+            parse_action("[2021-01-01T10:00:00+0100] [ALPM] reinstalled foo (1.0-1)\r"),
+            action("foo", "1.0-1")
+        );
+    }
+
+    #[test]
+    fn other_lines() {
+        for line in &[
+            "[2021-01-01T10:00:00+0100] [ALPM] installed foo (1.0-1)",
+            "[2021-01-01T10:00:00+0100] [PACMAN] removed foo (1.0-1)",
+            "[2021-01-01T10:00:00+0100] [ALPM] removed foo",
+            "[2021-01-01T10:00:00+0100] [ALPM] transaction completed",
+        ] {
+            assert_eq!(parse_action(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn saved() {
+        let path = std::env::temp_dir().join(format!("archdiff-pacman-log-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            &b"[2021-01-01T10:00:00+0100] [ALPM] warning: /mnt/etc/foo saved as /mnt/etc/foo.pacsave
+[2021-01-01T10:00:00+0100] [ALPM] removed foo (1.0-1)
+[2021-01-02T10:00:00+0100] [ALPM] warning: /mnt/etc/b\xffr saved as /mnt/etc/b\xffr.pacsave
+[2021-01-02T10:00:00+0100] [ALPM] warning: /mnt/etc/foo saved as /mnt/etc/foo.pacsave
+[2021-01-02T10:00:00+0100] [ALPM] upgraded foo (1.1-1 -> 2.0-1)
+"[..],
+        )
+        .unwrap();
+        let saved = saved_files(&path, "/mnt").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            saved[Path::new("etc/foo.pacsave")],
+            [
+                ("foo".to_string(), "1.0-1".to_string()),
+                ("foo".to_string(), "1.1-1".to_string())
+            ]
+        );
+        let bar = Path::new(OsStr::from_bytes(b"etc/b\xffr.pacsave"));
+        assert_eq!(saved[bar], [("foo".to_string(), "1.1-1".to_string())]);
+    }
+}
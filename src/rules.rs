@@ -0,0 +1,254 @@
+use crate::change::Status;
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// the rules from a directory of ignore files. each line is a gitignore
+/// pattern or `@name` for every change owned by the package name, and can be
+/// limited to some statuses with a `[?D]` prefix.
+pub struct IgnoreRules {
+    // the path rules that apply to each status, in the order they were read
+    paths: HashMap<Status, Gitignore>,
+    packages: Vec<(Vec<Status>, String, Rule)>,
+    // the line each path rule was read from, the last one wins like in
+    // gitignore when a file repeats a rule
+    lines: HashMap<(Status, PathBuf, String), usize>,
+}
+
+/// where an ignore rule was read from
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub file: PathBuf,
+    pub line: usize,
+    pub rule: String,
+    /// whether the rule re-includes the path, with a !
+    pub whitelist: bool,
+}
+
+// splits off the statuses a rule applies to. a line only has a status prefix
+// if the brackets hold status chars and are followed by whitespace, so glob
+// character classes like [abc]* keep working.
+fn split_statuses(line: &str) -> (Vec<Status>, &str) {
+    let prefix = line
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once(']'))
+        .filter(|(_, rule)| rule.starts_with(char::is_whitespace))
+        .and_then(|(chars, rule)| {
+            let statuses = chars
+                .chars()
+                .filter(|c| *c != ',')
+                .map(Status::from_char)
+                .collect::<Option<Vec<_>>>()?;
+            Some((statuses, rule.trim_start())).filter(|(s, _)| !s.is_empty())
+        });
+    prefix.unwrap_or_else(|| (Status::ALL.to_vec(), line))
+}
+
+impl IgnoreRules {
+    /// reads every file in dir, in name order
+    pub fn load(dir: &str) -> Result<Self> {
+        let mut builders: HashMap<_, _> = Status::ALL
+            .iter()
+            .map(|s| (*s, GitignoreBuilder::new("/")))
+            .collect();
+        let mut packages = vec![];
+        let mut lines = HashMap::new();
+        let mut files = std::fs::read_dir(dir)
+            .with_context(|| format!("failed to read directory {}", dir))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<PathBuf>>>()
+            .with_context(|| format!("failed to read directory {}", dir))?;
+        files.sort();
+        for file in files {
+            let contents = std::fs::read_to_string(&file)
+                .with_context(|| format!("failed to read {}", file.display()))?;
+            for (n, line) in contents.lines().enumerate() {
+                let (statuses, rule) = split_statuses(line);
+                if let Some(name) = rule.strip_prefix('@') {
+                    let source = Rule {
+                        file: file.clone(),
+                        line: n + 1,
+                        rule: line.to_string(),
+                        whitelist: false,
+                    };
+                    packages.push((statuses, name.trim().to_string(), source));
+                    continue;
+                }
+                for status in statuses {
+                    builders
+                        .get_mut(&status)
+                        .unwrap()
+                        .add_line(Some(file.clone()), rule)?;
+                    // keyed like Glob::original, which keeps an escaped
+                    // trailing space
+                    let original = if rule.ends_with("\\ ") {
+                        rule
+                    } else {
+                        rule.trim_end()
+                    };
+                    lines.insert((status, file.clone(), original.to_string()), n + 1);
+                }
+            }
+        }
+        let paths = builders
+            .into_iter()
+            .map(|(status, builder)| Ok((status, builder.build()?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            paths,
+            packages,
+            lines,
+        })
+    }
+
+    /// whether a path relative to the root is ignored for a status. rules
+    /// match paths as absolute paths on the checked system, so /etc/foo
+    /// matches etc/foo even with --root /mnt. a path is ignored if it or any
+    /// of its parent directories is ignored. like git, a whitelist rule can't
+    /// re-include a path inside an ignored directory.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool, status: Status) -> bool {
+        self.matched(path, is_dir, status).is_ignore()
+    }
+
+    fn matched(
+        &self,
+        path: &Path,
+        is_dir: bool,
+        status: Status,
+    ) -> Match<&ignore::gitignore::Glob> {
+        let ignored = &self.paths[&status];
+        // rebuilt from its components to drop a trailing /
+        let path: PathBuf = Path::new("/").join(path).components().collect();
+        let mut parents: Vec<_> = path.ancestors().skip(1).collect();
+        parents.pop();
+        parents
+            .iter()
+            .rev()
+            .map(|parent| ignored.matched(parent, true))
+            .find(|m| m.is_ignore())
+            .unwrap_or_else(|| ignored.matched(&path, is_dir))
+    }
+
+    /// the rule deciding whether a path relative to the root is ignored for a
+    /// status, which may be a whitelist rule
+    pub fn rule_for(&self, path: &Path, is_dir: bool, status: Status) -> Option<Rule> {
+        let glob = match self.matched(path, is_dir, status) {
+            Match::None => return None,
+            Match::Ignore(glob) | Match::Whitelist(glob) => glob,
+        };
+        let file = glob.from()?.to_path_buf();
+        // 0 if the line can't be found, which only happens if the key no
+        // longer matches Glob::original
+        let key = (status, file.clone(), glob.original().to_string());
+        let line = self.lines.get(&key).copied().unwrap_or_default();
+        Some(Rule {
+            file,
+            line,
+            rule: glob.original().to_string(),
+            whitelist: glob.is_whitelist(),
+        })
+    }
+
+    /// whether a path relative to the root is ignored for a status, without
+    /// looking at its parents. used while walking, where ignored parents
+    /// have already been skipped.
+    pub(crate) fn is_ignored_leaf(&self, path: &Path, is_dir: bool, status: Status) -> bool {
+        self.paths[&status]
+            .matched(Path::new("/").join(path), is_dir)
+            .is_ignore()
+    }
+
+    /// whether changes with a status owned by the named package are ignored
+    pub fn is_package_ignored(&self, name: &str, status: Status) -> bool {
+        self.package_rule_for(name, status).is_some()
+    }
+
+    /// the rule ignoring changes with a status owned by the named package
+    pub fn package_rule_for(&self, name: &str, status: Status) -> Option<Rule> {
+        self.packages
+            .iter()
+            .find(|(statuses, n, _)| n == name && statuses.contains(&status))
+            .map(|(_, _, rule)| rule.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_prefix() {
+        let (statuses, rule) = split_statuses("[?D] /foo");
+        assert_eq!(statuses, [Status::Untracked, Status::Deleted]);
+        assert_eq!(rule, "/foo");
+        let (statuses, rule) = split_statuses("[?,D]\t /foo");
+        assert_eq!(statuses, [Status::Untracked, Status::Deleted]);
+        assert_eq!(rule, "/foo");
+    }
+
+    #[test]
+    fn character_class_is_a_glob() {
+        for line in &["[abc]*", "[?D]/foo", "[Q] foo", "[] foo", "[D"] {
+            let (statuses, rule) = split_statuses(line);
+            assert_eq!(statuses, Status::ALL.to_vec(), "{}", line);
+            assert_eq!(rule, *line);
+        }
+    }
+
+    #[test]
+    fn load_rules() {
+        let dir = std::env::temp_dir().join(format!("archdiff-rules-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("rules");
+        std::fs::write(
+            &file,
+            "# comment
+[D] /usr/share/locale
+/etc/foo*
+!/etc/foo.keep
+[abc]*.log
+@linux
+[DR] @firefox
+/etc/bar\x20\x20
+",
+        )
+        .unwrap();
+        let rules = IgnoreRules::load(dir.to_str().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let locale = Path::new("usr/share/locale/de/x.mo");
+        assert!(rules.is_ignored(locale, false, Status::Deleted));
+        assert!(!rules.is_ignored(locale, false, Status::Untracked));
+        assert!(rules.is_ignored(Path::new("etc/foobar"), false, Status::Untracked));
+        assert!(!rules.is_ignored(Path::new("etc/foo.keep"), false, Status::Untracked));
+        assert!(rules.is_ignored(Path::new("var/a.log"), false, Status::Backup));
+        assert!(!rules.is_ignored(Path::new("var/d.log"), false, Status::Backup));
+        assert!(rules.is_ignored(Path::new("etc/bar"), false, Status::Backup));
+
+        assert!(rules.is_package_ignored("linux", Status::Backup));
+        assert!(rules.is_package_ignored("firefox", Status::Deleted));
+        assert!(!rules.is_package_ignored("firefox", Status::Backup));
+        let rule = rules.package_rule_for("firefox", Status::Repo).unwrap();
+        assert_eq!((rule.line, rule.rule.as_str()), (7, "[DR] @firefox"));
+
+        let rule = rules.rule_for(locale, false, Status::Deleted).unwrap();
+        assert_eq!(rule.file, file);
+        assert_eq!((rule.line, rule.rule.as_str()), (2, "/usr/share/locale"));
+        assert!(!rule.whitelist);
+        let rule = rules
+            .rule_for(Path::new("etc/foo.keep"), false, Status::Untracked)
+            .unwrap();
+        assert_eq!((rule.line, rule.rule.as_str()), (4, "!/etc/foo.keep"));
+        assert!(rule.whitelist);
+        let rule = rules
+            .rule_for(Path::new("etc/bar"), false, Status::Untracked)
+            .unwrap();
+        assert_eq!((rule.line, rule.rule.as_str()), (8, "/etc/bar"));
+        assert!(rules
+            .rule_for(Path::new("etc/other"), false, Status::Untracked)
+            .is_none());
+    }
+}
@@ -0,0 +1,1254 @@
+use crate::cache::HashCache;
+use crate::change::{Change, Details, Status};
+use crate::escape::escape;
+use crate::localdb;
+use crate::mtree;
+use crate::package;
+use crate::pacman_conf::Patterns;
+use crate::pacman_log;
+use crate::rules::{IgnoreRules, Rule};
+use anyhow::{anyhow, Context, Result};
+use ignore::{WalkBuilder, WalkState};
+use log::{debug, error, warn};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt::Display;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
+
+/// where to look for packages, the repo & ignore rules
+pub struct Config {
+    pub root: String,
+    pub dbpath: String,
+    /// the pacman log, used to find the packages pacsave files came from
+    pub log_file: String,
+    pub cache_dirs: Vec<String>,
+    /// repo dirs layered over each other, later ones win for paths they
+    /// have a copy or tombstone of. %h is replaced by the hostname and dirs
+    /// that don't exist are skipped.
+    pub repos: Vec<String>,
+    /// the hostname for %h, read from the root's /etc/hostname if None
+    pub hostname: Option<String>,
+    pub ignore: String,
+    pub no_extract: Vec<String>,
+    pub no_upgrade: Vec<String>,
+    /// verify package files against the local database mtree
+    pub mtree: bool,
+    /// limit the checks to these paths, relative to the root. empty checks
+    /// everything.
+    pub paths: Vec<PathBuf>,
+    /// limit the checks to the files & backup entries of these packages.
+    /// empty checks everything, otherwise no untracked files are reported.
+    pub packages: Vec<String>,
+    /// only run the checks for these statuses, empty runs all checks
+    pub statuses: Vec<Status>,
+    /// file to remember hashes in between runs, None disables the cache
+    pub cache: Option<String>,
+    /// ignore the hashes remembered in the cache
+    pub rehash: bool,
+    /// also hash untracked & pacsave files, to tell runs apart
+    pub hash_untracked: bool,
+    /// threads used to walk the root & repo, 0 picks a number based on the
+    /// available cpus. everything else runs on the global rayon pool.
+    pub threads: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            root: "/".to_string(),
+            dbpath: "/var/lib/pacman/".to_string(),
+            log_file: "/var/log/pacman.log".to_string(),
+            cache_dirs: vec!["/var/cache/pacman/pkg/".to_string()],
+            repos: vec!["/usr/share/archdiff".to_string()],
+            hostname: None,
+            ignore: "/etc/archdiff/ignore".to_string(),
+            no_extract: vec![],
+            no_upgrade: vec![],
+            mtree: false,
+            paths: vec![],
+            packages: vec![],
+            statuses: vec![],
+            cache: Some("/var/cache/archdiff/hashes".to_string()),
+            rehash: false,
+            hash_untracked: false,
+            threads: 0,
+        }
+    }
+}
+
+/// why a path is or isn't reported
+#[derive(Debug)]
+pub struct Explanation {
+    /// the name & version of every package owning the path
+    pub packages: Vec<(String, String)>,
+    /// the packages checking the path like a backup file, with the md5 they
+    /// expect
+    pub backup: Vec<(String, String)>,
+    /// the md5 of the file on disk when it is checked like a backup file
+    pub actual_hash: Option<String>,
+    pub no_extract: bool,
+    pub no_upgrade: bool,
+    /// the repo copy of the path in the topmost layer that has one
+    pub repo: Option<PathBuf>,
+    /// the tombstone marking the path or one of its parents as deleted, if
+    /// the topmost layer deciding about the path has one instead of a copy
+    pub tombstone: Option<PathBuf>,
+    /// the entry for the path in the topmost repo manifest listing it
+    pub manifest: Option<mtree::Entry>,
+    /// the ignore rules deciding whether each status is ignored
+    pub rules: Vec<(Status, Rule)>,
+}
+
+pub struct Scanner {
+    alpm: alpm::Alpm,
+    ignore: IgnoreRules,
+    root: String,
+    dbpath: String,
+    log_file: String,
+    cache_dirs: Vec<String>,
+    repos: Vec<String>,
+    no_extract: Patterns,
+    no_upgrade: Patterns,
+    mtree: bool,
+    paths: Vec<PathBuf>,
+    packages: HashSet<String>,
+    statuses: Vec<Status>,
+    cache: HashCache,
+    hash_untracked: bool,
+    threads: usize,
+}
+
+// the hostname of the system below root, or of the running system
+fn read_hostname(root: &str) -> Option<String> {
+    [
+        Path::new(root).join("etc/hostname"),
+        PathBuf::from("/proc/sys/kernel/hostname"),
+    ]
+    .iter()
+    .filter_map(|path| std::fs::read_to_string(path).ok())
+    .map(|name| name.trim().to_string())
+    .find(|name| !name.is_empty())
+}
+
+// a path that couldn't be checked. paths outside the root, like the repo or
+// the local database, are kept absolute.
+fn error_change<E: Display>(root: &str, path: &Path, err: E) -> Change {
+    Change {
+        details: Details {
+            error: Some(format!("{:#}", err)),
+            ..Details::default()
+        },
+        ..Change::new(
+            Status::Error,
+            path.strip_prefix(root).unwrap_or(path).to_path_buf(),
+        )
+    }
+}
+
+fn is_not_found(err: &anyhow::Error) -> bool {
+    err.root_cause()
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound)
+}
+
+// the path an error from walking a directory tree is about, if it has one
+fn walk_error_path(err: &ignore::Error) -> Option<&Path> {
+    match err {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            walk_error_path(err)
+        }
+        ignore::Error::Partial(errs) => errs.iter().find_map(walk_error_path),
+        ignore::Error::Loop { child, .. } => Some(child),
+        _ => None,
+    }
+}
+
+// the dir pacman keeps an installed package's files & mtree in
+fn local_entry(dbpath: &str, name: &str, version: &str) -> PathBuf {
+    Path::new(dbpath)
+        .join("local")
+        .join(format!("{}-{}", name, version))
+}
+
+// appended to a path in the repo to mark it as deleted on purpose
+const TOMBSTONE: &str = ".archdiff-tombstone";
+
+/// the mtree file at the top of a repo layer holding the mode, owner &
+/// symlink target files should have
+pub const MANIFEST: &str = ".archdiff.mtree";
+
+// whether a path or one of its parents is marked as deleted in the repo
+fn is_tombstoned(tombstones: &HashMap<PathBuf, usize>, path: &Path) -> bool {
+    !tombstones.is_empty() && path.ancestors().any(|p| tombstones.contains_key(p))
+}
+
+// whether a path relative to the root is inside one of the given paths
+fn in_scope(paths: &[PathBuf], path: &Path) -> bool {
+    paths.is_empty() || paths.iter().any(|p| path.starts_with(p))
+}
+
+/// a path relative to the root, without a leading or trailing /. . & ..
+/// parts are resolved without looking at the file system, like pacman does
+/// for its own paths, and .. never leaves the root.
+pub fn relative(path: &Path) -> PathBuf {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::ParentDir => {
+                relative.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+        }
+    }
+    relative
+}
+
+// the target of a symlink, None for anything else
+fn read_link_if_symlink(path: &Path) -> Result<Option<PathBuf>> {
+    let meta = std::fs::symlink_metadata(path)
+        .with_context(|| format!("failed to stat {}", path.display()))?;
+    if !meta.file_type().is_symlink() {
+        return Ok(None);
+    }
+    let target = std::fs::read_link(path)
+        .with_context(|| format!("failed to read link {}", path.display()))?;
+    Ok(Some(target))
+}
+
+// the path without a suffix like .pacnew, if it ends in it
+fn strip_suffix<'a>(path: &'a Path, suffix: &str) -> Option<&'a Path> {
+    let bytes = path.as_os_str().as_bytes();
+    let base = bytes.strip_suffix(suffix.as_bytes())?;
+    Some(Path::new(OsStr::from_bytes(base)))
+}
+
+// the path a pacsave was saved from & how often pacman rotated it since, 0
+// for .pacsave, 1 for .pacsave.1 & so on
+fn strip_pacsave(path: &Path) -> Option<(&Path, usize)> {
+    if let Some(base) = strip_suffix(path, ".pacsave") {
+        return Some((base, 0));
+    }
+    let bytes = path.as_os_str().as_bytes();
+    let dot = bytes.iter().rposition(|b| *b == b'.')?;
+    let digits = &bytes[dot + 1..];
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let n = std::str::from_utf8(digits).ok()?.parse().ok()?;
+    let base = strip_suffix(Path::new(OsStr::from_bytes(&bytes[..dot])), ".pacsave")?;
+    Some((base, n))
+}
+
+// the path with a suffix like .pacnew appended to its last component
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path.as_os_str());
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// pacman lists directories with a trailing /
+fn is_dir_entry(path: &Path) -> bool {
+    path.as_os_str().as_bytes().ends_with(b"/")
+}
+
+// whether the checks for a status should run
+fn wants(statuses: &[Status], status: Status) -> bool {
+    statuses.is_empty() || statuses.contains(&status)
+}
+
+// the mode, owner & symlink target of a file that differ from its mtree entry
+fn attribute_mismatches(
+    fp: &Path,
+    meta: &std::fs::Metadata,
+    entry: &mtree::Entry,
+) -> Result<Vec<&'static str>> {
+    let is_link = meta.file_type().is_symlink();
+    let mut mismatches = vec![];
+    if entry.mode.is_some_and(|m| m != meta.mode() & 0o7777) && !is_link {
+        mismatches.push("mode");
+    }
+    if entry.uid.is_some_and(|u| u != meta.uid()) {
+        mismatches.push("uid");
+    }
+    if entry.gid.is_some_and(|g| g != meta.gid()) {
+        mismatches.push("gid");
+    }
+    if entry.kind == mtree::Kind::Link || entry.link.is_some() {
+        let target = if is_link {
+            Some(
+                std::fs::read_link(fp)
+                    .with_context(|| format!("failed to read link {}", fp.display()))?,
+            )
+        } else {
+            None
+        };
+        if entry.link != target {
+            mismatches.push("link");
+        }
+    }
+    Ok(mismatches)
+}
+
+// compares a file on disk with its mtree entry, noting the attributes that
+// differ. missing files are left to the deleted files check.
+fn mtree_change(cache: &HashCache, root: &str, entry: &mtree::Entry) -> Option<Change> {
+    let fp = Path::new(root).join(&entry.path);
+    let meta = match std::fs::symlink_metadata(&fp) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            let err = format!("failed to stat {}: {}", fp.display(), err);
+            return Some(error_change(root, &fp, err));
+        }
+    };
+    let ft = meta.file_type();
+    let kind = if ft.is_dir() {
+        mtree::Kind::Dir
+    } else if ft.is_symlink() {
+        mtree::Kind::Link
+    } else {
+        mtree::Kind::File
+    };
+    let mut change = Change::new(Status::Mtree, entry.path.clone());
+    let mut mismatches = vec![];
+    if kind != entry.kind {
+        mismatches.push("type");
+    } else {
+        mismatches = match attribute_mismatches(&fp, &meta, entry) {
+            Ok(mismatches) => mismatches,
+            Err(err) => return Some(error_change(root, &fp, err)),
+        };
+        match kind {
+            mtree::Kind::File => {
+                if entry.size.is_some_and(|s| s != meta.len()) {
+                    mismatches.push("size");
+                } else if let Some(expected) = &entry.sha256 {
+                    let actual = match cache.sha256(&fp) {
+                        Ok(actual) => actual,
+                        Err(err) => return Some(error_change(root, &fp, err)),
+                    };
+                    if actual != *expected {
+                        mismatches.push("sha256");
+                        change.details.expected_hash = Some(expected.clone());
+                        change.details.actual_hash = Some(actual);
+                    }
+                }
+            }
+            mtree::Kind::Link | mtree::Kind::Dir => (),
+        }
+    }
+    if mismatches.is_empty() {
+        return None;
+    }
+    change.details.note = Some(mismatches.join(", "));
+    Some(change)
+}
+
+// a pacnew compared to the live file it is the new version of
+fn pacnew_change(cache: &HashCache, root: &str, path: &Path, base: &Path) -> Change {
+    let new_fp = Path::new(root).join(path);
+    let new_hash = match cache.md5(&new_fp) {
+        Ok(hash) => hash,
+        Err(err) => return error_change(root, &new_fp, err),
+    };
+    let fp = Path::new(root).join(base);
+    let (note, actual_hash) = match cache.md5(&fp) {
+        Ok(hash) if hash == new_hash => (format!("same as /{}", escape(base)), Some(hash)),
+        Ok(hash) => (format!("differs from /{}", escape(base)), Some(hash)),
+        Err(err) if is_not_found(&err) => (format!("/{} is missing", escape(base)), None),
+        Err(err) => return error_change(root, &fp, err),
+    };
+    Change {
+        details: Details {
+            expected_hash: Some(new_hash),
+            actual_hash,
+            note: Some(note),
+            ..Details::default()
+        },
+        ..Change::new(Status::Pacnew, path.to_path_buf())
+    }
+}
+
+impl Scanner {
+    pub fn new(config: Config) -> Result<Self> {
+        let mut root = config.root;
+        if !root.ends_with('/') {
+            root.push('/');
+        }
+        let mut hostname = config.hostname;
+        if hostname.is_none() && config.repos.iter().any(|r| r.contains("%h")) {
+            hostname = read_hostname(&root);
+        }
+        let mut repos = vec![];
+        for repo in config.repos {
+            let mut repo = match (&hostname, repo.contains("%h")) {
+                (Some(hostname), true) => repo.replace("%h", hostname),
+                (None, true) => {
+                    warn!("skipping repo {}, the hostname is unknown", repo);
+                    continue;
+                }
+                (_, false) => repo,
+            };
+            if !repo.ends_with('/') {
+                repo.push('/');
+            }
+            repos.push(repo);
+        }
+        debug!(
+            "using root {} dbpath {} cache dirs {:?} repos {:?}",
+            root, config.dbpath, config.cache_dirs, repos
+        );
+        Ok(Self {
+            alpm: alpm::Alpm::new(root.as_bytes(), config.dbpath.as_bytes())?,
+            ignore: IgnoreRules::load(&config.ignore)?,
+            no_extract: Patterns::new(&config.no_extract)?,
+            no_upgrade: Patterns::new(&config.no_upgrade)?,
+            root,
+            dbpath: config.dbpath,
+            log_file: config.log_file,
+            cache_dirs: config.cache_dirs,
+            repos,
+            mtree: config.mtree,
+            paths: config.paths.iter().map(|p| relative(p)).collect(),
+            packages: config.packages.into_iter().collect(),
+            statuses: config.statuses,
+            cache: match config.cache {
+                Some(path) => HashCache::open(path, config.rehash),
+                None => HashCache::disabled(),
+            },
+            hash_untracked: config.hash_untracked,
+            threads: config.threads,
+        })
+    }
+
+    /// the root dir, always ending in a /
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// the repo layers from bottom to top, always ending in a /. some may
+    /// not exist.
+    pub fn repos(&self) -> &[String] {
+        &self.repos
+    }
+
+    // the dirs to walk below base, limited to the paths being checked
+    fn scoped_dirs(&self, base: &str) -> Vec<PathBuf> {
+        let dirs = if self.paths.is_empty() {
+            vec![PathBuf::from(base)]
+        } else {
+            self.paths.iter().map(|p| Path::new(base).join(p)).collect()
+        };
+        dirs.into_iter()
+            .filter(|p| std::fs::symlink_metadata(p).is_ok())
+            .collect()
+    }
+
+    // the tombstones in a repo for the paths being checked & their parents,
+    // which a walk limited to the paths doesn't come across
+    fn scoped_tombstones(&self, repo: &str) -> Vec<PathBuf> {
+        self.paths
+            .iter()
+            .flat_map(|p| p.ancestors())
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(|p| with_suffix(p, TOMBSTONE))
+            .filter(|p| std::fs::symlink_metadata(Path::new(repo).join(p)).is_ok())
+            .collect()
+    }
+
+    // the copy of a path in the topmost layer that has one, or the tombstone
+    // of the path or one of its parents if that layer deletes it instead
+    fn repo_lookup(&self, path: &Path) -> (Option<PathBuf>, Option<PathBuf>) {
+        for repo in self.repos.iter().rev() {
+            let copy = Path::new(repo).join(path);
+            if std::fs::symlink_metadata(&copy).is_ok_and(|m| !m.is_dir()) {
+                return (Some(copy), None);
+            }
+            let tombstone = path
+                .ancestors()
+                .filter(|p| !p.as_os_str().is_empty())
+                .map(|p| Path::new(repo).join(with_suffix(p, TOMBSTONE)))
+                .find(|p| std::fs::symlink_metadata(p).is_ok());
+            if tombstone.is_some() {
+                return (None, tombstone);
+            }
+        }
+        (None, None)
+    }
+
+    // walks directory trees below base in parallel, pruning paths ignored for
+    // all of the statuses if there are any, and returns every path that isn't
+    // a directory, relative to base, along with the paths that couldn't be
+    // read
+    fn walk(
+        &self,
+        base: &str,
+        dirs: &[PathBuf],
+        statuses: &[Status],
+    ) -> (Vec<PathBuf>, Vec<Change>) {
+        let root = &self.root;
+        let rules = &self.ignore;
+        let (first, rest) = match dirs.split_first() {
+            Some(dirs) => dirs,
+            None => return (vec![], vec![]),
+        };
+        let mut builder = WalkBuilder::new(first);
+        rest.iter().for_each(|dir| {
+            builder.add(dir);
+        });
+        let ignored = |path: &Path, is_dir: bool, leaf: bool| {
+            !statuses.is_empty()
+                && statuses.iter().all(|status| {
+                    if leaf {
+                        rules.is_ignored_leaf(path, is_dir, *status)
+                    } else {
+                        rules.is_ignored(path, is_dir, *status)
+                    }
+                })
+        };
+        let (tx, rx) = mpsc::channel();
+        builder
+            .standard_filters(false)
+            .threads(self.threads)
+            .build_parallel()
+            .run(|| {
+                let tx = tx.clone();
+                Box::new(move |result| {
+                    // the receiver outlives the walk
+                    let de = match result {
+                        Ok(de) => de,
+                        Err(err) => {
+                            match walk_error_path(&err) {
+                                Some(path) => {
+                                    let rel = path.strip_prefix(base).unwrap_or(path);
+                                    if !ignored(rel, false, false) {
+                                        tx.send(Err(error_change(root, path, &err))).unwrap();
+                                    }
+                                }
+                                None => error!("{}", err),
+                            }
+                            return WalkState::Continue;
+                        }
+                    };
+                    let is_dir = de.file_type().is_some_and(|ft| ft.is_dir());
+                    let path = de.path().strip_prefix(base).unwrap_or(de.path());
+                    // parents of the dirs being walked still need checking,
+                    // below those ignored directories are never entered
+                    if ignored(path, is_dir, de.depth() > 0) {
+                        return WalkState::Skip;
+                    }
+                    if !is_dir {
+                        tx.send(Ok(path.to_path_buf())).unwrap();
+                    }
+                    WalkState::Continue
+                })
+            });
+        drop(tx);
+        let mut found = vec![];
+        let mut errors = vec![];
+        for result in rx {
+            match result {
+                Ok(path) => found.push(path),
+                Err(change) => errors.push(change),
+            }
+        }
+        (found, errors)
+    }
+
+    /// runs all checks, yielding the differences sorted by path
+    pub fn scan(&self) -> impl Iterator<Item = Change> {
+        // the name & version of every package, files refer to their owner by
+        // its index in here
+        let mut pkgs = vec![];
+        let mut pkg_files = HashMap::new();
+        let mut pkg_backup_files = HashMap::new();
+        let mut mtree_pkgs = vec![];
+        let mut all = vec![];
+        let paths = &self.paths;
+        let statuses = &self.statuses;
+        for pkg in self.alpm.localdb().pkgs() {
+            let i = pkgs.len();
+            let name = pkg.name();
+            if !self.packages.is_empty() && !self.packages.contains(name) {
+                continue;
+            }
+            let version = pkg.version().as_str();
+            pkgs.push((name.to_string(), version.to_string()));
+            if self.mtree
+                && wants(statuses, Status::Mtree)
+                && !self.ignore.is_package_ignored(name, Status::Mtree)
+            {
+                mtree_pkgs.push(i);
+            }
+            let files = match localdb::read_files(&self.dbpath, name, version) {
+                Ok(files) => files,
+                Err(err) => {
+                    let entry = local_entry(&self.dbpath, name, version);
+                    all.push(error_change(&self.root, &entry, err));
+                    localdb::Files::default()
+                }
+            };
+            pkg_files.extend(
+                files
+                    .files
+                    .iter()
+                    .filter(|f| in_scope(paths, f))
+                    .map(|f| (f.clone(), i)),
+            );
+            pkg_backup_files.extend(
+                files
+                    .backup
+                    .into_iter()
+                    .filter(|(b, _)| in_scope(paths, b))
+                    .map(|(b, hash)| (b, (i, hash))),
+            );
+
+            // NoUpgrade files are never overwritten by pacman, so like backup
+            // files they are checked against the hash from the package
+            let no_upgrade: HashSet<_> = files
+                .files
+                .iter()
+                .filter(|f| pkg_files.contains_key(*f) && !pkg_backup_files.contains_key(*f))
+                .filter(|f| self.no_upgrade.is_match(f))
+                .collect();
+            if !no_upgrade.is_empty() {
+                let entries = match mtree::read_local(&self.dbpath, name, version) {
+                    Ok(entries) => entries,
+                    Err(err) => {
+                        let entry = local_entry(&self.dbpath, name, version);
+                        all.push(error_change(&self.root, &entry, err));
+                        vec![]
+                    }
+                };
+                pkg_backup_files.extend(
+                    entries
+                        .into_iter()
+                        .filter(|e| no_upgrade.contains(&e.path))
+                        .filter_map(|e| Some((e.path, (i, e.md5?)))),
+                );
+            }
+        }
+
+        let pkgs = &pkgs;
+        let root = &self.root;
+        let rules = &self.ignore;
+        let cache = &self.cache;
+        let dbpath = &self.dbpath;
+        let repos = &self.repos;
+
+        // repo files that have been changed. the walk isn't pruned since a
+        // repo file still counts as tracked when its changes are ignored.
+        // when limited to packages only the copies & tombstones of their files
+        // are checked. tombstones for parents of the paths or files being
+        // checked are looked up too, the walk doesn't come across those.
+        let mut repo_paths = vec![];
+        let mut candidates = HashSet::new();
+        if !self.packages.is_empty() {
+            for p in pkg_files.keys() {
+                candidates.insert(p.clone());
+                let p = relative(p);
+                candidates.extend(
+                    p.ancestors()
+                        .filter(|a| !a.as_os_str().is_empty())
+                        .map(|a| with_suffix(a, TOMBSTONE)),
+                );
+            }
+        }
+        for (layer, repo) in repos.iter().enumerate() {
+            if self.packages.is_empty() {
+                let (found, errors) = self.walk(repo, &self.scoped_dirs(repo), &[]);
+                all.extend(errors);
+                repo_paths.extend(found.into_iter().map(|p| (layer, p)));
+                let tombstones = self.scoped_tombstones(repo);
+                repo_paths.extend(tombstones.into_iter().map(|p| (layer, p)));
+            } else {
+                repo_paths.extend(
+                    candidates
+                        .iter()
+                        .filter(|p| {
+                            std::fs::symlink_metadata(Path::new(repo).join(p))
+                                .is_ok_and(|m| !m.is_dir())
+                        })
+                        .map(|p| (layer, p.clone())),
+                );
+            }
+        }
+        // merges the layers, a later layer wins & within a layer a copy wins
+        // over a tombstone. tombstones mark paths that are deleted on purpose,
+        // they & the manifests aren't copies of files themselves.
+        let mut merged: HashMap<PathBuf, (usize, bool)> = HashMap::new();
+        for (layer, path) in repo_paths {
+            if path == Path::new(MANIFEST) {
+                continue;
+            }
+            let (path, is_copy) = match strip_suffix(&path, TOMBSTONE) {
+                Some(base) => (base.to_path_buf(), false),
+                None => (path, true),
+            };
+            let entry = merged.entry(path).or_insert((layer, is_copy));
+            if *entry < (layer, is_copy) {
+                *entry = (layer, is_copy);
+            }
+        }
+        let tombstones: HashMap<_, _> = merged
+            .iter()
+            .filter(|(_, (_, is_copy))| !is_copy)
+            .map(|(path, (layer, _))| (path.clone(), *layer))
+            .collect();
+        let repo_files: Vec<_> = merged
+            .into_iter()
+            .filter(|(_, (_, is_copy))| *is_copy)
+            // copies below a directory a later layer deletes are left out
+            .filter(|(path, (layer, _))| {
+                !path
+                    .ancestors()
+                    .skip(1)
+                    .any(|p| tombstones.get(p).is_some_and(|t| t > layer))
+            })
+            .map(|(path, (layer, _))| {
+                let owner = match pkg_backup_files.remove(&path) {
+                    Some((i, _)) => Some(i),
+                    None => pkg_files.get(&path).copied(),
+                };
+                (path, layer, owner)
+            })
+            .collect();
+        if wants(statuses, Status::Repo) || wants(statuses, Status::Missing) {
+            all.par_extend(repo_files.par_iter().filter_map(|(path, layer, owner)| {
+                let fp = Path::new(root).join(path);
+                let meta = match std::fs::symlink_metadata(&fp) {
+                    Ok(meta) => Some(meta),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(err) => {
+                        let err = format!("failed to stat {}: {}", fp.display(), err);
+                        return Some(error_change(root, &fp, err));
+                    }
+                };
+                let status = match meta {
+                    Some(_) => Status::Repo,
+                    None => Status::Missing,
+                };
+                if !wants(statuses, status) || rules.is_ignored(path, false, status) {
+                    return None;
+                }
+                // sync copies symlinks as they are, so their targets are
+                // compared instead of the contents they point to
+                let repo_fp = Path::new(&repos[*layer]).join(path);
+                let repo_link = match read_link_if_symlink(&repo_fp) {
+                    Ok(link) => link,
+                    Err(err) => return Some(error_change(root, &repo_fp, err)),
+                };
+                let repo_hash = match repo_link {
+                    Some(_) => None,
+                    None => match cache.md5(&repo_fp) {
+                        Ok(hash) => Some(hash),
+                        Err(err) => return Some(error_change(root, &repo_fp, err)),
+                    },
+                };
+                let owner = owner.map(|i| &pkgs[i]);
+                if meta.is_none() {
+                    let change = Change {
+                        details: Details {
+                            expected_hash: repo_hash,
+                            ..Details::default()
+                        },
+                        ..Change::new(Status::Missing, path.clone())
+                    };
+                    return Some(change.owned_by(owner));
+                }
+                let link = match read_link_if_symlink(&fp) {
+                    Ok(link) => link,
+                    Err(err) => return Some(error_change(root, &fp, err)),
+                };
+                let details = match (repo_link, link) {
+                    (Some(expected), Some(actual)) if expected == actual => return None,
+                    (Some(expected), Some(actual)) => Details {
+                        note: Some(format!(
+                            "links to {}, the repo copy to {}",
+                            escape(&actual),
+                            escape(&expected)
+                        )),
+                        ..Details::default()
+                    },
+                    (Some(_), None) => Details {
+                        note: Some("the repo copy is a symlink".to_string()),
+                        ..Details::default()
+                    },
+                    (None, Some(_)) => Details {
+                        expected_hash: repo_hash,
+                        note: Some("the repo copy isn't a symlink".to_string()),
+                        ..Details::default()
+                    },
+                    (None, None) => {
+                        let actual_hash = match cache.md5(&fp) {
+                            Ok(hash) => hash,
+                            Err(err) => return Some(error_change(root, &fp, err)),
+                        };
+                        if repo_hash.as_ref() == Some(&actual_hash) {
+                            return None;
+                        }
+                        Details {
+                            expected_hash: repo_hash,
+                            actual_hash: Some(actual_hash),
+                            ..Details::default()
+                        }
+                    }
+                };
+                let change = Change {
+                    details,
+                    ..Change::new(Status::Repo, path.clone())
+                };
+                Some(change.owned_by(owner))
+            }));
+        }
+        let repo_files: HashSet<_> = repo_files
+            .iter()
+            .map(|(path, _, _)| path.as_path())
+            .collect();
+
+        // package files that differ from the mtree. backup files are left to
+        // their own check since they are expected to change, and files
+        // tracked or deleted in the repo to the repo checks.
+        all.par_extend(mtree_pkgs.into_par_iter().flat_map(|i| {
+            let (name, version) = &pkgs[i];
+            let entries = match mtree::read_local(dbpath, name, version) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    let entry = local_entry(dbpath, name, version);
+                    return vec![error_change(root, &entry, err)];
+                }
+            };
+            entries
+                .into_par_iter()
+                .filter_map(|entry| {
+                    // skips the root itself and metadata like .PKGINFO
+                    let name = entry.path.as_os_str().as_bytes();
+                    if name.is_empty()
+                        || name.starts_with(b".")
+                        || !in_scope(paths, &entry.path)
+                        || pkg_backup_files.contains_key(&entry.path)
+                        || repo_files.contains(entry.path.as_path())
+                        || is_tombstoned(&tombstones, &entry.path)
+                    {
+                        return None;
+                    }
+                    let is_dir = entry.kind == mtree::Kind::Dir;
+                    if rules.is_ignored(&entry.path, is_dir, Status::Mtree) {
+                        return None;
+                    }
+                    mtree_change(cache, root, &entry).map(|change| change.owned_by(Some(&pkgs[i])))
+                })
+                .collect::<Vec<_>>()
+        }));
+
+        // paths marked as deleted that have come back
+        if wants(statuses, Status::Repo) {
+            all.extend(tombstones.iter().filter_map(|(path, layer)| {
+                let meta = std::fs::symlink_metadata(Path::new(root).join(path)).ok()?;
+                if rules.is_ignored(path, meta.is_dir(), Status::Repo) {
+                    return None;
+                }
+                let tombstone = Path::new(&repos[*layer]).join(with_suffix(path, TOMBSTONE));
+                let change = Change {
+                    details: Details {
+                        note: Some(format!("marked as deleted by {}", escape(&tombstone))),
+                        ..Details::default()
+                    },
+                    ..Change::new(Status::Repo, path.clone())
+                };
+                Some(change.owned_by(pkg_files.get(path).map(|i| &pkgs[*i])))
+            }));
+        }
+
+        // files whose mode, owner or symlink target differs from the
+        // manifests, where later layers win. missing files are left to the
+        // other checks.
+        if wants(statuses, Status::Metadata) {
+            let mut entries = HashMap::new();
+            for repo in repos {
+                let manifest = Path::new(repo).join(MANIFEST);
+                match mtree::read(&manifest) {
+                    Ok(layer) => entries.extend(
+                        layer
+                            .into_iter()
+                            .map(|e| (relative(&e.path), (manifest.clone(), e))),
+                    ),
+                    Err(err) if is_not_found(&err) => (),
+                    Err(err) => all.push(error_change(root, &manifest, err)),
+                }
+            }
+            let packages = &self.packages;
+            all.par_extend(
+                entries
+                    .into_par_iter()
+                    .filter_map(|(path, (manifest, entry))| {
+                        if !in_scope(paths, &path)
+                            || (!packages.is_empty() && !pkg_files.contains_key(&path))
+                        {
+                            return None;
+                        }
+                        let fp = Path::new(root).join(&path);
+                        let meta = match std::fs::symlink_metadata(&fp) {
+                            Ok(meta) => meta,
+                            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+                            Err(err) => {
+                                let err = format!("failed to stat {}: {}", fp.display(), err);
+                                return Some(error_change(root, &fp, err));
+                            }
+                        };
+                        if rules.is_ignored(&path, meta.is_dir(), Status::Metadata) {
+                            return None;
+                        }
+                        let mismatches = match attribute_mismatches(&fp, &meta, &entry) {
+                            Ok(mismatches) if mismatches.is_empty() => return None,
+                            Ok(mismatches) => mismatches,
+                            Err(err) => return Some(error_change(root, &fp, err)),
+                        };
+                        let owner = pkg_files.get(&path).map(|i| &pkgs[*i]);
+                        let change = Change {
+                            details: Details {
+                                note: Some(format!(
+                                    "{} differs from {}",
+                                    mismatches.join(", "),
+                                    escape(&manifest)
+                                )),
+                                ..Details::default()
+                            },
+                            ..Change::new(Status::Metadata, path)
+                        };
+                        Some(change.owned_by(owner))
+                    }),
+            );
+        }
+
+        // untracked files on disk, with pacnew & pacsave files told apart.
+        // files tracked in the repo are left to the repo check above. without
+        // the walk every packaged file is checked by the deleted files check
+        // instead.
+        let walk_statuses: Vec<_> = [Status::Untracked, Status::Pacnew, Status::Pacsave]
+            .iter()
+            .copied()
+            .filter(|s| wants(statuses, *s))
+            .collect();
+        if !self.packages.is_empty() {
+            // without the walk, look for pacnews next to the packages' files
+            if wants(statuses, Status::Pacnew) {
+                for (path, i) in &pkg_files {
+                    let pacnew = with_suffix(path, ".pacnew");
+                    if std::fs::symlink_metadata(Path::new(root).join(&pacnew)).is_ok()
+                        && !rules.is_ignored(&pacnew, false, Status::Pacnew)
+                    {
+                        all.push(
+                            pacnew_change(cache, root, &pacnew, path).owned_by(Some(&pkgs[*i])),
+                        );
+                    }
+                }
+            }
+        } else if !walk_statuses.is_empty() {
+            let dirs = self.scoped_dirs(&self.root);
+            let (found, errors) = self.walk(&self.root, &dirs, &walk_statuses);
+            all.extend(errors);
+            let mut pacsaves = vec![];
+            for path in &found {
+                if pkg_files.contains_key(path)
+                    || repo_files.contains(path.as_path())
+                    || is_tombstoned(&tombstones, path)
+                {
+                    continue;
+                }
+                let pacnew = strip_suffix(path, ".pacnew");
+                let pacsave = strip_pacsave(path);
+                let status = match (pacnew, pacsave) {
+                    (Some(_), _) => Status::Pacnew,
+                    (_, Some(_)) => Status::Pacsave,
+                    _ => Status::Untracked,
+                };
+                if !walk_statuses.contains(&status) || rules.is_ignored(path, false, status) {
+                    continue;
+                }
+                match (pacnew, pacsave) {
+                    (Some(base), _) => {
+                        let owner = pkg_files.get(base).map(|i| &pkgs[*i]);
+                        all.push(pacnew_change(cache, root, path, base).owned_by(owner));
+                    }
+                    (_, Some((base, n))) => pacsaves.push((path, base, n)),
+                    _ => all.push(Change::new(status, path.clone())),
+                }
+            }
+            if !pacsaves.is_empty() {
+                let saved = match pacman_log::saved_files(&self.log_file, root) {
+                    Ok(saved) => saved,
+                    Err(err) => {
+                        all.push(error_change(root, Path::new(&self.log_file), err));
+                        HashMap::new()
+                    }
+                };
+                all.extend(pacsaves.into_iter().map(|(path, base, n)| {
+                    // the log names every pacsave as it was before rotating.
+                    // when the log doesn't go back far enough the oldest
+                    // package that saved the file is the best guess.
+                    let owner = saved
+                        .get(&with_suffix(base, ".pacsave"))
+                        .and_then(|pkgs| pkgs.iter().rev().nth(n).or_else(|| pkgs.first()));
+                    Change {
+                        details: Details {
+                            note: Some(format!("saved from /{}", escape(base))),
+                            ..Details::default()
+                        },
+                        ..Change::new(Status::Pacsave, path.clone())
+                    }
+                    .owned_by(owner)
+                }));
+            }
+            for path in &found {
+                pkg_files.remove(path);
+            }
+        }
+
+        // deleted files from packages, unless they are marked as deleted in
+        // the repo
+        let no_extract = &self.no_extract;
+        let tombstones = &tombstones;
+        if wants(statuses, Status::Deleted) {
+            all.par_extend(pkg_files.into_par_iter().filter_map(|(p, i)| {
+                let fp = Path::new(root).join(&p);
+                if rules.is_ignored(&p, is_dir_entry(&p), Status::Deleted)
+                    || no_extract.is_match(&p)
+                    || is_tombstoned(tombstones, &p)
+                {
+                    None
+                } else {
+                    match std::fs::metadata(&fp)
+                        .with_context(|| format!("failed to stat {}", fp.display()))
+                    {
+                        Err(err) if is_not_found(&err) => {
+                            Some(Change::new(Status::Deleted, p).owned_by(Some(&pkgs[i])))
+                        }
+                        Err(err) => Some(error_change(root, &fp, err)),
+                        Ok(_) => None,
+                    }
+                }
+            }));
+        }
+
+        // backup files that have been changed
+        if wants(statuses, Status::Backup) {
+            all.par_extend(pkg_backup_files.into_par_iter().filter_map(
+                |(p, (i, expected_hash))| {
+                    let fp = Path::new(root).join(&p);
+                    if rules.is_ignored(&p, false, Status::Backup)
+                        || rules.is_package_ignored(&pkgs[i].0, Status::Backup)
+                    {
+                        None
+                    } else {
+                        match cache.md5(&fp) {
+                            Ok(actual_hash) if actual_hash == expected_hash => None,
+                            Ok(actual_hash) => {
+                                let change = Change {
+                                    details: Details {
+                                        expected_hash: Some(expected_hash),
+                                        actual_hash: Some(actual_hash),
+                                        ..Details::default()
+                                    },
+                                    ..Change::new(Status::Backup, p)
+                                };
+                                Some(change.owned_by(Some(&pkgs[i])))
+                            }
+                            // left to the deleted files check
+                            Err(err) if is_not_found(&err) => None,
+                            Err(err) => Some(error_change(root, &fp, err)),
+                        }
+                    }
+                },
+            ));
+        }
+
+        if self.hash_untracked {
+            all.par_iter_mut()
+                .filter(|c| matches!(c.status, Status::Untracked | Status::Pacsave))
+                .for_each(|c| match cache.md5(Path::new(root).join(&c.path)) {
+                    Ok(hash) => c.details.actual_hash = Some(hash),
+                    Err(err) => c.details.error = Some(format!("{:#}", err)),
+                });
+        }
+
+        // a scan limited to some paths, statuses or packages only looked at
+        // part of the files, the others keep their cached hashes. this keeps
+        // the hook run after every transaction from emptying the cache.
+        let partial =
+            !self.paths.is_empty() || !self.statuses.is_empty() || !self.packages.is_empty();
+        if let Err(err) = self.cache.save(partial) {
+            warn!("failed to save hash cache: {:#}", err);
+        }
+
+        // paths that couldn't be checked are reported unless ignored for E
+        all.retain(|c| {
+            let package_ignored = c
+                .package
+                .as_ref()
+                .is_some_and(|name| rules.is_package_ignored(name, c.status));
+            let error_ignored = c.status == Status::Error
+                && c.path.is_relative()
+                && rules.is_ignored(&c.path, false, Status::Error);
+            !package_ignored && !error_ignored
+        });
+        all.sort_by(|a, b| a.path.cmp(&b.path));
+        all.into_iter()
+    }
+
+    /// explains which packages, repo copies & ignore rules decide whether a
+    /// path is reported
+    pub fn explain(&self, path: &Path) -> Result<Explanation> {
+        let path = relative(path);
+        let fp = Path::new(&self.root).join(&path);
+        let is_dir = std::fs::symlink_metadata(&fp).is_ok_and(|m| m.is_dir());
+        let name = if is_dir {
+            with_suffix(&path, "/")
+        } else {
+            path.clone()
+        };
+        let no_upgrade = self.no_upgrade.is_match(&name);
+
+        let mut packages = vec![];
+        let mut backup = vec![];
+        for pkg in self.alpm.localdb().pkgs() {
+            if !matches!(
+                pkg.files().contains(name.as_os_str().as_bytes()),
+                Ok(Some(_))
+            ) {
+                continue;
+            }
+            let version = pkg.version().to_string();
+            let files = localdb::read_files(&self.dbpath, pkg.name(), &version)?;
+            match files.backup.into_iter().find(|(b, _)| *b == name) {
+                Some((_, hash)) => backup.push((pkg.name().to_string(), hash)),
+                None if no_upgrade => {
+                    let entries = mtree::read_local(&self.dbpath, pkg.name(), &version)?;
+                    if let Some(md5) = entries
+                        .into_iter()
+                        .find(|e| e.path == name)
+                        .and_then(|e| e.md5)
+                    {
+                        backup.push((pkg.name().to_string(), md5));
+                    }
+                }
+                None => (),
+            }
+            packages.push((pkg.name().to_string(), version));
+        }
+        let actual_hash = if backup.is_empty() {
+            None
+        } else {
+            self.cache.md5(&fp).ok()
+        };
+
+        let (repo, tombstone) = self.repo_lookup(&path);
+        let mut manifest = None;
+        for repo in self.repos.iter().rev() {
+            match mtree::read(Path::new(repo).join(MANIFEST)) {
+                Ok(entries) => manifest = entries.into_iter().find(|e| relative(&e.path) == path),
+                Err(err) if is_not_found(&err) => (),
+                Err(err) => return Err(err),
+            }
+            if manifest.is_some() {
+                break;
+            }
+        }
+
+        let mut rules = vec![];
+        for status in Status::ALL.iter().copied() {
+            if let Some(rule) = self.ignore.rule_for(&path, is_dir, status) {
+                rules.push((status, rule));
+            }
+            rules.extend(
+                packages
+                    .iter()
+                    .filter_map(|(pkg, _)| self.ignore.package_rule_for(pkg, status))
+                    .map(|rule| (status, rule)),
+            );
+        }
+
+        Ok(Explanation {
+            packages,
+            backup,
+            actual_hash,
+            no_extract: self.no_extract.is_match(&name),
+            no_upgrade,
+            repo,
+            tombstone,
+            manifest,
+            rules,
+        })
+    }
+
+    /// the original contents of a file, from the repo if it is tracked there
+    /// or else from the owning package in the cache dirs. returns a name
+    /// describing where the contents came from along with the contents.
+    pub fn original(&self, path: &Path) -> Result<(String, Vec<u8>)> {
+        let path = relative(path);
+        if let (Some(repo_path), _) = self.repo_lookup(&path) {
+            let contents = std::fs::read(&repo_path)
+                .with_context(|| format!("failed to read {}", repo_path.display()))?;
+            return Ok((escape(&repo_path).into_owned(), contents));
+        }
+        let pkg = self
+            .alpm
+            .localdb()
+            .pkgs()
+            .iter()
+            .find(|pkg| {
+                matches!(
+                    pkg.files().contains(path.as_os_str().as_bytes()),
+                    Ok(Some(_))
+                )
+            })
+            .ok_or_else(|| anyhow!("{}{} is not owned by any package", self.root, escape(&path)))?;
+        let version = pkg.version().to_string();
+        let arch = pkg.arch().unwrap_or("any");
+        let cached = package::find_cached(&self.cache_dirs, pkg.name(), &version, arch)
+            .ok_or_else(|| {
+                anyhow!(
+                    "package file for {}-{} not found in {}",
+                    pkg.name(),
+                    version,
+                    self.cache_dirs.join(", ")
+                )
+            })?;
+        Ok((
+            format!("{}-{}: /{}", pkg.name(), version, escape(&path)),
+            package::extract(&cached, &path)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_resolves_dots() {
+        assert_eq!(relative(Path::new("/etc/foo/")), Path::new("etc/foo"));
+        assert_eq!(relative(Path::new("./etc/./foo")), Path::new("etc/foo"));
+        assert_eq!(
+            relative(Path::new("/etc/../usr/lib/x")),
+            Path::new("usr/lib/x")
+        );
+        assert_eq!(relative(Path::new("/../../etc")), Path::new("etc"));
+    }
+
+    #[test]
+    fn rotated_pacsaves() {
+        let strip = |path| strip_pacsave(Path::new(path));
+        assert_eq!(strip("etc/foo.pacsave"), Some((Path::new("etc/foo"), 0)));
+        assert_eq!(strip("etc/foo.pacsave.2"), Some((Path::new("etc/foo"), 2)));
+        assert_eq!(strip("etc/foo.pacsave."), None);
+        assert_eq!(strip("etc/foo.pacsave.x"), None);
+        assert_eq!(strip("etc/foo.2"), None);
+    }
+}
@@ -0,0 +1,50 @@
+use crate::change::{Change, Drift, Status};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// writes changes as json, so a later scan can be compared with them
+pub fn save<P: AsRef<Path>>(path: P, changes: &[Change]) -> Result<()> {
+    let path = path.as_ref();
+    let json = serde_json::to_vec(changes)?;
+    std::fs::write(path, json).with_context(|| format!("failed to write {}", path.display()))
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Change>> {
+    let path = path.as_ref();
+    let json = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_slice(&json).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// the changes that appeared, disappeared or changed hash since the
+/// snapshot, sorted by path. changes are matched by status & path, and
+/// changed if either the expected or the actual hash differs.
+pub fn since(snapshot: Vec<Change>, changes: Vec<Change>) -> Vec<Change> {
+    let mut old: HashMap<(Status, PathBuf), Change> = snapshot
+        .into_iter()
+        .map(|c| ((c.status, c.path.clone()), c))
+        .collect();
+    let mut drifted = vec![];
+    for c in changes {
+        let drift = match old.remove(&(c.status, c.path.clone())) {
+            None => Drift::Appeared,
+            Some(o)
+                if (&o.details.expected_hash, &o.details.actual_hash)
+                    != (&c.details.expected_hash, &c.details.actual_hash) =>
+            {
+                Drift::Changed
+            }
+            Some(_) => continue,
+        };
+        drifted.push(Change {
+            drift: Some(drift),
+            ..c
+        });
+    }
+    drifted.extend(old.into_values().map(|c| Change {
+        drift: Some(Drift::Disappeared),
+        ..c
+    }));
+    drifted.sort_by(|a, b| a.path.cmp(&b.path));
+    drifted
+}